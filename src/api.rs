@@ -1,57 +1,767 @@
-use crate::config::RobotConfig;
-use async_std::sync::RwLock;
-use std::sync::Arc;
-use tide::{listener::ToListener, Body, Request};
+use crate::{
+    auth::{ApiClient, ApiToken, Authenticator},
+    calibration::CalibrationStore,
+    config::{ConfigSources, RobotConfig},
+    history::{self, ConfigHistory},
+    input::{ConnectionState, JoystickMessage, NetworkJoysticks},
+    macros::MacroRequests,
+    migrate, persist,
+    profiles::{merge_patch, Profile},
+    schema,
+    status::RobotStatus,
+    tls,
+};
+use anyhow::Context;
+use async_std::{stream::StreamExt, sync::RwLock, task};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+    net::ToSocketAddrs,
+    sync::{Arc, Mutex},
+};
+use tide::{
+    http::headers, listener::ToListener, Body, Request, Response, StatusCode,
+};
+use tide_rustls::TlsListener;
+use tide_websockets::{Message, WebSocket, WebSocketConnection};
+
+/// Touch-friendly virtual joystick page, served at `/joystick`
+const JOYSTICK_PAGE: &str = include_str!("../static/joystick.html");
 
 /// HTTP API that allows users to read robot state and mutate the robot config.
 /// Because of this, the config needs to be wrapped in a read-write lock, so
-/// that mutations can be synchronized with reads during the main loop. This API
-/// provides NO authentication or authorization!
+/// that mutations can be synchronized with reads during the main loop. If API
+/// tokens are configured, every request has to authenticate with one (see
+/// [Authenticator]). Otherwise, anyone who can reach the API can control the
+/// robot!
 pub struct Api {
     app: tide::Server<State>,
 }
 
 impl Api {
     /// Set up (but do not launch!) the HTTP server
-    pub fn new(config: Arc<RwLock<RobotConfig>>) -> Self {
-        let mut app = tide::with_state(State { config });
-        app.at("/config").get(get_config).post(post_config);
+    pub fn new(
+        config_sources: ConfigSources,
+        config: Arc<RwLock<RobotConfig>>,
+        history: Arc<Mutex<ConfigHistory>>,
+        status: Arc<RwLock<RobotStatus>>,
+        calibration: Arc<std::sync::RwLock<CalibrationStore>>,
+        network_joysticks: Option<NetworkJoysticks>,
+        macro_requests: MacroRequests,
+    ) -> Self {
+        let mut app = tide::with_state(State {
+            config_sources,
+            config,
+            history,
+            persist_lock: Arc::default(),
+            status,
+            calibration_save_lock: Arc::default(),
+            calibration,
+            macro_requests,
+        });
+        app.at("/config")
+            .get(get_config)
+            .post(post_config)
+            .patch(patch_config);
+        app.at("/config/schema").get(get_config_schema);
+        app.at("/config/history").get(get_history);
+        app.at("/config/history/diff").get(diff_history);
+        app.at("/config/history/:id").get(get_revision);
+        app.at("/config/history/:id/rollback").post(rollback_config);
+        app.at("/config/*path")
+            .get(get_config_path)
+            .put(put_config_path)
+            .patch(patch_config_path);
+        app.at("/status").get(get_status);
+        app.at("/calibration").get(get_calibration);
+        app.at("/calibration/start").post(start_calibration);
+        app.at("/calibration/finish").post(finish_calibration);
+        app.at("/calibration/:uuid").delete(reset_calibration);
+        app.at("/macros").get(get_macros);
+        app.at("/macros/stop").post(stop_macro);
+        app.at("/macros/:name/run").post(run_macro);
+        app.at("/profiles").get(get_profiles);
+        app.at("/profiles/deactivate").post(deactivate_profile);
+        app.at("/profiles/:name/activate").post(activate_profile);
+
+        // Only serve the virtual joystick if network input is enabled
+        if let Some(network_joysticks) = network_joysticks {
+            app.at("/joystick").get(get_joystick_page);
+            // The page sends its token as a second subprotocol (see
+            // [auth::TOKEN_PROTOCOL_PREFIX]), but only this one is accepted
+            app.at("/joystick/ws").get(
+                WebSocket::new(move |req: Request<State>, stream| {
+                    joystick_socket(network_joysticks.clone(), req, stream)
+                })
+                .with_protocols(&["robot-joystick"]),
+            );
+        }
         Self { app }
     }
 
-    /// Launch the HTTP server
+    /// Require every request to authenticate with one of these tokens. With
+    /// None, the API is left open, which is logged as a warning.
+    pub fn set_tokens(&mut self, tokens: Option<Vec<ApiToken>>) {
+        match tokens {
+            Some(tokens) => {
+                if tokens.is_empty() {
+                    log::warn!(
+                        "No API tokens are configured, so every API request \
+                        will be rejected"
+                    );
+                }
+                self.app.with(Authenticator::new(tokens));
+            }
+            None => log::warn!(
+                "API authentication is disabled, because api.tokens_path \
+                isn't set. Anyone who can reach the API can control the robot!"
+            ),
+        }
+    }
+
+    /// Launch the HTTP server. If TLS is enabled, it serves HTTPS, generating
+    /// a self-signed certificate first if needed and allowed.
     pub async fn run(self) -> anyhow::Result<()> {
-        // bruh
-        let host = self
-            .app
-            .state()
-            .config
-            .read()
-            .await
-            .api
-            .host
-            .as_str()
-            .to_listener()?;
-        log::info!("Starting API...");
-        Ok(self.app.listen(host).await?)
+        let config = self.app.state().config.read().await.api.clone();
+        if config.tls.enabled {
+            tls::ensure_cert(&config.tls)?;
+            // The builder quietly ignores addresses it can't resolve, so
+            // resolve them here to get a useful error
+            let addrs = config
+                .host
+                .to_socket_addrs()
+                .with_context(|| format!("Resolving {}", config.host))?
+                .collect::<Vec<_>>();
+            let listener = TlsListener::build()
+                .addrs(addrs.as_slice())
+                .cert(&config.tls.cert_path)
+                .key(&config.tls.key_path)
+                .finish()?;
+            log::info!("Starting API with HTTPS...");
+            Ok(self.app.listen(listener).await?)
+        } else {
+            let listener = config.host.as_str().to_listener()?;
+            log::info!("Starting API...");
+            Ok(self.app.listen(listener).await?)
+        }
     }
 }
 
 /// API state, accessible to every request
 #[derive(Clone, Debug)]
 struct State {
+    /// Where the config was loaded from. Changes are persisted to the base
+    /// config file.
+    config_sources: ConfigSources,
     config: Arc<RwLock<RobotConfig>>,
+    /// Every config applied so far. Only locked briefly.
+    history: Arc<Mutex<ConfigHistory>>,
+    /// Held while a config change is applied and persisted
+    persist_lock: Arc<async_std::sync::Mutex<()>>,
+    status: Arc<RwLock<RobotStatus>>,
+    calibration: Arc<std::sync::RwLock<CalibrationStore>>,
+    /// Held while a calibration is changed and saved, so saves hit the disk
+    /// in the same order as the changes
+    calibration_save_lock: Arc<async_std::sync::Mutex<()>>,
+    macro_requests: MacroRequests,
+}
+
+/// Read the robot's config. The response has an `ETag`, which can be sent
+/// back in `If-Match` when updating the config, to make sure nobody else
+/// changed it in the meantime.
+async fn get_config(req: Request<State>) -> tide::Result<Response> {
+    let config = req.state().config.read().await;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&*config)?)
+        .header(headers::ETAG, config_etag(&config)?)
+        .build())
+}
+
+/// Read one part of the config, by JSON Pointer, e.g. `/config/input/drive`
+async fn get_config_path(req: Request<State>) -> tide::Result<Response> {
+    let pointer = config_pointer(&req)?;
+    let config = req.state().config.read().await;
+    let value = serde_json::to_value(&*config)?;
+    let value = value.pointer(&pointer).ok_or_else(|| {
+        tide::Error::from_str(
+            StatusCode::NotFound,
+            format!("No config value at {}", pointer),
+        )
+    })?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(value)?)
+        .header(headers::ETAG, config_etag(&config)?)
+        .build())
+}
+
+/// Replace the robot's config. Configs from older versions are migrated
+/// first. If the new config is invalid, it's rejected with a 400 and a list
+/// of problems. If persistence is enabled, the change is also written to the
+/// config file.
+async fn post_config(mut req: Request<State>) -> tide::Result<Response> {
+    let mut body: serde_json::Value = req.body_json().await?;
+    migrate::migrate("Request body", &mut body)
+        .map_err(|err| tide::Error::new(StatusCode::BadRequest, err))?;
+    let new_config = parse_config(body)?;
+    replace_config(&req, |_| Ok(new_config)).await
+}
+
+/// Update part of the config with a JSON merge patch
+/// ([RFC 7396](https://tools.ietf.org/html/rfc7396)), e.g.
+/// `{"drive": {"simulate": true}}`. Otherwise, this works like [post_config].
+async fn patch_config(mut req: Request<State>) -> tide::Result<Response> {
+    let patch: serde_json::Value = req.body_json().await?;
+    update_config(&req, |config| {
+        merge_patch(config, &patch);
+        Ok(())
+    })
+    .await
+}
+
+/// Replace one part of the config, by JSON Pointer, e.g. `PUT
+/// /config/input/drive`. The value's parent has to exist already.
+async fn put_config_path(mut req: Request<State>) -> tide::Result<Response> {
+    let value: serde_json::Value = req.body_json().await?;
+    let pointer = config_pointer(&req)?;
+    update_config(&req, |config| set_pointer(config, &pointer, value)).await
+}
+
+/// Set the value at a JSON Pointer. If there's no value there yet, it's added
+/// to its parent, which has to be an object.
+fn set_pointer(
+    config: &mut serde_json::Value,
+    pointer: &str,
+    value: serde_json::Value,
+) -> tide::Result<()> {
+    if let Some(target) = config.pointer_mut(pointer) {
+        *target = value;
+        return Ok(());
+    }
+    let (parent, key) = pointer.rsplit_once('/').unwrap_or_default();
+    match config.pointer_mut(parent) {
+        Some(serde_json::Value::Object(parent)) => {
+            parent.insert(key.replace("~1", "/").replace("~0", "~"), value);
+            Ok(())
+        }
+        _ => Err(tide::Error::from_str(
+            StatusCode::NotFound,
+            format!("No config table at {}", parent),
+        )),
+    }
+}
+
+/// Merge-patch one part of the config, by JSON Pointer. See [patch_config].
+async fn patch_config_path(mut req: Request<State>) -> tide::Result<Response> {
+    let patch: serde_json::Value = req.body_json().await?;
+    let pointer = config_pointer(&req)?;
+    update_config(&req, |config| {
+        let target = config.pointer_mut(&pointer).ok_or_else(|| {
+            tide::Error::from_str(
+                StatusCode::NotFound,
+                format!("No config value at {}", pointer),
+            )
+        })?;
+        merge_patch(target, &patch);
+        Ok(())
+    })
+    .await
+}
+
+/// Change the current config as JSON, then validate it and swap it in. See
+/// [replace_config].
+async fn update_config(
+    req: &Request<State>,
+    change: impl FnOnce(&mut serde_json::Value) -> tide::Result<()>,
+) -> tide::Result<Response> {
+    replace_config(req, |config| {
+        let mut value = serde_json::to_value(config)?;
+        change(&mut value)?;
+        parse_config(value)
+    })
+    .await
+}
+
+/// Build a new config from the current one with `change`, validate it and,
+/// if it's valid, swap it in and record it in the history. If persistence is
+/// enabled, the change is then written to the base config file. Responds
+/// with the new config, or a 400 and a list of problems if it's invalid.
+///
+/// The main loop can't run while the config is write-locked, so all the
+/// file work for persisting happens on a blocking task, outside the lock.
+async fn replace_config(
+    req: &Request<State>,
+    change: impl FnOnce(&RobotConfig) -> tide::Result<RobotConfig>,
+) -> tide::Result<Response> {
+    let state = req.state();
+    // Only one change is persisted at a time, so they reach the file in the
+    // same order they're applied
+    let _persisting = state.persist_lock.lock().await;
+    let target = if state.config.read().await.api.persist {
+        let sources = state.config_sources.clone();
+        let target = task::spawn_blocking(move || {
+            persist::PersistTarget::load(&sources)
+        })
+        .await
+        .map_err(|err| {
+            tide::Error::new(StatusCode::InternalServerError, err)
+        })?;
+        Some(target)
+    } else {
+        None
+    };
+
+    let mut config = state.config.write().await;
+    check_if_match(req, &config)?;
+    let new_config = change(&config)?;
+    let problems = new_config.validate();
+    if problems.has_errors() {
+        return Ok(Response::builder(StatusCode::BadRequest)
+            .body(Body::from_json(&problems)?)
+            .build());
+    }
+
+    // Persistence may have been turned on by a reload since we checked, in
+    // which case this change is only applied
+    let pending_write = match &target {
+        Some(target) if config.api.persist => {
+            Some(target.prepare(&config, &new_config).map_err(|err| {
+                // Changing values that another source overrides is the
+                // client's problem
+                let status = if err.is::<persist::OverriddenChanges>() {
+                    StatusCode::Conflict
+                } else {
+                    StatusCode::InternalServerError
+                };
+                tide::Error::new(status, err)
+            })?)
+        }
+        _ => None,
+    };
+    let response = Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&new_config)?)
+        .header(headers::ETAG, config_etag(&new_config)?)
+        .build();
+    state.history.lock().unwrap().record(
+        &new_config,
+        client_name(req).as_deref(),
+        format!("{} {}", req.method(), req.url().path()),
+    );
+    *config = new_config;
+    drop(config);
+
+    if let Some(pending_write) = pending_write {
+        task::spawn_blocking(move || pending_write.write())
+            .await
+            .map_err(|err| {
+                tide::Error::new(
+                    StatusCode::InternalServerError,
+                    err.context("The config was changed, but saving it failed"),
+                )
+            })?;
+    }
+    Ok(response)
+}
+
+/// Identify the client that made a request, for the config history: the name
+/// of its API token (if it used one) and its address
+fn client_name<T>(req: &Request<T>) -> Option<String> {
+    match (req.ext::<ApiClient>(), req.remote()) {
+        (Some(client), Some(remote)) => {
+            Some(format!("{} ({})", client.name, remote))
+        }
+        (Some(client), None) => Some(client.name.clone()),
+        (None, remote) => remote.map(String::from),
+    }
+}
+
+/// Deserialize a config from a request, rejecting it with a 422 if it
+/// doesn't fit
+fn parse_config(value: serde_json::Value) -> tide::Result<RobotConfig> {
+    serde_json::from_value(value)
+        .map_err(|err| tide::Error::new(StatusCode::UnprocessableEntity, err))
+}
+
+/// Get the JSON Pointer from a `/config/*path` route. The path is still
+/// percent-encoded in the URL, e.g. for keys with spaces.
+fn config_pointer(req: &Request<State>) -> tide::Result<String> {
+    decode_pointer(req.param("path")?)
+}
+
+/// Turn a percent-encoded URL path into a JSON Pointer
+fn decode_pointer(path: &str) -> tide::Result<String> {
+    let path = percent_decode_str(path)
+        .decode_utf8()
+        .map_err(|err| tide::Error::new(StatusCode::BadRequest, err))?;
+    Ok(format!("/{}", path.trim_end_matches('/')))
+}
+
+/// Get an entity tag for a config, which changes whenever the config does.
+/// It's a hash of the config as JSON, whose objects have sorted keys, so equal
+/// configs always get the same tag.
+fn config_etag(config: &RobotConfig) -> tide::Result<String> {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_value(config)?.to_string().hash(&mut hasher);
+    Ok(format!("\"{:016x}\"", hasher.finish()))
+}
+
+/// If the request has an `If-Match` header, make sure it matches the current
+/// config's ETag. Otherwise, someone else changed the config since the client
+/// read it, so the request is rejected with a 412. `If-Match` uses strong
+/// comparison, so weak (`W/`) tags never match.
+fn check_if_match(
+    req: &Request<State>,
+    config: &RobotConfig,
+) -> tide::Result<()> {
+    let if_match = match req.header(headers::IF_MATCH) {
+        Some(if_match) => if_match.as_str(),
+        None => return Ok(()),
+    };
+    let etag = config_etag(config)?;
+    if if_match_matches(if_match, &etag) {
+        Ok(())
+    } else {
+        Err(tide::Error::from_str(
+            StatusCode::PreconditionFailed,
+            format!(
+                "Config has changed (ETag is {}), re-read it and try again",
+                etag
+            ),
+        ))
+    }
+}
+
+/// Does an `If-Match` header value match an ETag? It can be a list of tags,
+/// or `*` to match anything.
+fn if_match_matches(if_match: &str, etag: &str) -> bool {
+    if_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+/// Get a JSON Schema describing the config
+async fn get_config_schema(_: Request<State>) -> tide::Result<Body> {
+    Body::from_json(&schema::config_schema())
+}
+
+/// List all config revisions in the history, oldest first
+async fn get_history(req: Request<State>) -> tide::Result<Body> {
+    Body::from_json(&req.state().history.lock().unwrap().list())
+}
+
+/// Read one config revision, including its full config
+async fn get_revision(req: Request<State>) -> tide::Result<Body> {
+    let id = revision_id(&req)?;
+    let history = req.state().history.lock().unwrap();
+    Body::from_json(find_revision(&history, id)?)
+}
+
+/// Query for [diff_history]
+#[derive(Debug, Deserialize)]
+struct DiffQuery {
+    /// The older revision
+    from: u64,
+    /// The newer revision. Defaults to the latest one.
+    to: Option<u64>,
+}
+
+/// List every value that changed between two revisions, e.g.
+/// `/config/history/diff?from=3&to=5`
+async fn diff_history(req: Request<State>) -> tide::Result<Body> {
+    let query: DiffQuery = req.query()?;
+    let history = req.state().history.lock().unwrap();
+    let from = find_revision(&history, query.from)?;
+    let to = match query.to {
+        Some(id) => find_revision(&history, id)?,
+        None => history.latest().unwrap_or(from),
+    };
+    Body::from_json(&history::diff_revisions(from, to)?)
+}
+
+/// Go back to the config from an earlier revision. This is validated and
+/// recorded like any other config change, so it can be undone too.
+async fn rollback_config(req: Request<State>) -> tide::Result<Response> {
+    let id = revision_id(&req)?;
+    let new_config = find_revision(&req.state().history.lock().unwrap(), id)?
+        .config
+        .clone();
+    replace_config(&req, |_| Ok(new_config)).await
+}
+
+/// Get the revision ID from a `/config/history/:id` route
+fn revision_id(req: &Request<State>) -> tide::Result<u64> {
+    req.param("id")?.parse().map_err(|_| {
+        tide::Error::from_str(StatusCode::BadRequest, "Invalid revision ID")
+    })
+}
+
+/// Get a revision from the history, or a 404 if it's not there (anymore)
+fn find_revision(
+    history: &ConfigHistory,
+    id: u64,
+) -> tide::Result<&history::Revision> {
+    history.get(id).ok_or_else(|| {
+        tide::Error::from_str(
+            StatusCode::NotFound,
+            format!("No config revision {}", id),
+        )
+    })
+}
+
+/// Read the robot's live status
+async fn get_status(req: Request<State>) -> tide::Result<Body> {
+    Body::from_json(&req.state().status.read().await as &RobotStatus)
+}
+
+/// Read all gamepad calibrations, plus the in-progress calibration (if any)
+async fn get_calibration(req: Request<State>) -> tide::Result<Body> {
+    Body::from_json(&*req.state().calibration.read().unwrap())
+}
+
+/// Body for [start_calibration]
+#[derive(Debug, Deserialize)]
+struct StartCalibration {
+    /// The gamepad slot whose gamepad should be calibrated
+    gamepad: String,
+}
+
+/// Start calibrating the gamepad in a slot. The sticks should be at rest when
+/// this is called. Move them through their full range, then call
+/// [finish_calibration].
+async fn start_calibration(mut req: Request<State>) -> tide::Result<Body> {
+    let body: StartCalibration = req.body_json().await?;
+    let uuid = match req.state().status.read().await.gamepads.get(&body.gamepad)
+    {
+        Some(ConnectionState::Connected { uuid, .. }) => uuid.clone(),
+        _ => {
+            return Err(tide::Error::from_str(
+                StatusCode::NotFound,
+                format!("No gamepad connected for slot {:?}", body.gamepad),
+            ))
+        }
+    };
+    Body::from_json(req.state().calibration.write().unwrap().start(uuid))
+}
+
+/// Finish the in-progress calibration and save it
+async fn finish_calibration(req: Request<State>) -> tide::Result<Body> {
+    let _saving = req.state().calibration_save_lock.lock().await;
+    let (calibration, pending_save) = req
+        .state()
+        .calibration
+        .write()
+        .unwrap()
+        .finish()
+        .map_err(|err| tide::Error::new(StatusCode::Conflict, err))?;
+    task::spawn_blocking(move || pending_save.save()).await?;
+    Body::from_json(&calibration)
+}
+
+/// Delete the calibration for a gamepad, by UUID
+async fn reset_calibration(req: Request<State>) -> tide::Result<Body> {
+    let uuid = req.param("uuid")?;
+    let _saving = req.state().calibration_save_lock.lock().await;
+    let reset = req.state().calibration.write().unwrap().reset(uuid)?;
+    match reset {
+        None => Err(tide::Error::from_str(
+            StatusCode::NotFound,
+            format!("No calibration for gamepad {}", uuid),
+        )),
+        Some((calibration, pending_save)) => {
+            task::spawn_blocking(move || pending_save.save()).await?;
+            Body::from_json(&calibration)
+        }
+    }
+}
+
+/// Get the config with the active profile applied, which is what the robot
+/// actually runs with
+fn resolved_config(config: &RobotConfig) -> tide::Result<Cow<'_, RobotConfig>> {
+    config
+        .resolve_profile()
+        .map_err(|err| tide::Error::new(StatusCode::InternalServerError, err))
 }
 
-/// Read the robot's config
-async fn get_config(req: Request<State>) -> tide::Result<Body> {
-    Body::from_json(&req.state().config.read().await as &RobotConfig)
+/// Read all macros, including those from the active profile
+async fn get_macros(req: Request<State>) -> tide::Result<Body> {
+    let config = req.state().config.read().await;
+    Body::from_json(&resolved_config(&config)?.input.macros.sequences)
 }
 
-/// Update the robot's config
-async fn post_config(mut req: Request<State>) -> tide::Result<Body> {
-    // Grab the write lock, then update the whole config
-    let new_config: RobotConfig = req.body_json().await?;
-    *req.state().config.write().await = new_config.clone();
-    Body::from_json(&new_config)
+/// Start a macro, by name. It starts on the next loop iteration, replacing
+/// any macro that's already running.
+async fn run_macro(req: Request<State>) -> tide::Result<StatusCode> {
+    let name = req.param("name")?;
+    let config = req.state().config.read().await;
+    if !resolved_config(&config)?
+        .input
+        .macros
+        .sequences
+        .contains_key(name)
+    {
+        return Err(tide::Error::from_str(
+            StatusCode::NotFound,
+            format!("No macro named {:?}", name),
+        ));
+    }
+    req.state().macro_requests.run(name.into());
+    Ok(StatusCode::Accepted)
+}
+
+/// Cancel the running macro, if any
+async fn stop_macro(req: Request<State>) -> tide::Result<StatusCode> {
+    req.state().macro_requests.stop();
+    Ok(StatusCode::Accepted)
+}
+
+/// Response for [get_profiles]
+#[derive(Debug, Serialize)]
+struct Profiles<'a> {
+    /// Name of the active profile, if any
+    active: Option<&'a str>,
+    profiles: &'a BTreeMap<String, Profile>,
+}
+
+/// Read all configured profiles, and which one is active
+async fn get_profiles(req: Request<State>) -> tide::Result<Body> {
+    let config = req.state().config.read().await;
+    Body::from_json(&Profiles {
+        active: config.profile.as_deref(),
+        profiles: &config.profiles,
+    })
+}
+
+/// Switch to a profile, by name. Responds with the updated config.
+async fn activate_profile(req: Request<State>) -> tide::Result<Response> {
+    let name = req.param("name")?;
+    replace_config(&req, |config| {
+        if !config.profiles.contains_key(name) {
+            return Err(tide::Error::from_str(
+                StatusCode::NotFound,
+                format!("No profile named {:?}", name),
+            ));
+        }
+        let mut new_config = config.clone();
+        new_config.profile = Some(name.into());
+        Ok(new_config)
+    })
+    .await
+}
+
+/// Go back to the base config, with no profile. Responds with the updated
+/// config.
+async fn deactivate_profile(req: Request<State>) -> tide::Result<Response> {
+    replace_config(&req, |config| {
+        let mut new_config = config.clone();
+        new_config.profile = None;
+        Ok(new_config)
+    })
+    .await
+}
+
+/// Serve the virtual joystick page
+async fn get_joystick_page(_: Request<State>) -> tide::Result<Response> {
+    Ok(Response::builder(StatusCode::Ok)
+        .body(JOYSTICK_PAGE)
+        .content_type(tide::http::mime::HTML)
+        .build())
+}
+
+/// Handle a WebSocket connection from the virtual joystick page. The page
+/// identifies itself with a `device` query parameter. Each message is a JSON
+/// [JoystickMessage]. The joystick is removed when the connection closes.
+async fn joystick_socket(
+    network_joysticks: NetworkJoysticks,
+    req: Request<State>,
+    mut stream: WebSocketConnection,
+) -> tide::Result<()> {
+    let device = req
+        .url()
+        .query_pairs()
+        .find(|(key, _)| key == "device")
+        .map(|(_, device)| device.into_owned());
+    let id = network_joysticks
+        .connect(req.remote().unwrap_or("unknown"), device.as_deref());
+    while let Some(Ok(message)) = stream.next().await {
+        match message {
+            Message::Text(text) => {
+                match serde_json::from_str::<JoystickMessage>(&text) {
+                    Ok(message) => network_joysticks.update(id, message),
+                    Err(err) => {
+                        log::warn!("Invalid joystick message: {}", err)
+                    }
+                }
+            }
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    network_joysticks.disconnect(id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_decode_pointer() {
+        assert_eq!(decode_pointer("input/drive").unwrap(), "/input/drive");
+        assert_eq!(decode_pointer("input/drive/").unwrap(), "/input/drive");
+        assert_eq!(
+            decode_pointer("input/gamepads/my%20pad").unwrap(),
+            "/input/gamepads/my pad"
+        );
+        // JSON Pointer escapes are left for serde_json to decode
+        assert_eq!(decode_pointer("a~1b/c~0d").unwrap(), "/a~1b/c~0d");
+        assert_eq!(
+            decode_pointer("bad%FF").unwrap_err().status(),
+            StatusCode::BadRequest
+        );
+    }
+
+    #[test]
+    fn test_set_pointer() {
+        let mut config = json!({"input": {"gamepads": {"driver": 0}}});
+        set_pointer(&mut config, "/input/gamepads/driver", json!(1)).unwrap();
+        // New keys are added to their parent, with escapes decoded
+        set_pointer(&mut config, "/input/gamepads/a~1b~0c", json!(2)).unwrap();
+        assert_eq!(
+            config,
+            json!({"input": {"gamepads": {"driver": 1, "a/b~c": 2}}})
+        );
+        assert_eq!(
+            set_pointer(&mut config, "/input/nope/driver", json!(1))
+                .unwrap_err()
+                .status(),
+            StatusCode::NotFound
+        );
+    }
+
+    #[test]
+    fn test_etag() {
+        let config = RobotConfig::test_default();
+        let etag = config_etag(&config).unwrap();
+        assert!(etag.starts_with('"') && etag.ends_with('"'), "{}", etag);
+        assert_eq!(config_etag(&config.clone()).unwrap(), etag);
+        let mut changed = config;
+        changed.drive.simulate = !changed.drive.simulate;
+        assert_ne!(config_etag(&changed).unwrap(), etag);
+    }
+
+    #[test]
+    fn test_if_match() {
+        let etag = "\"0123456789abcdef\"";
+        assert!(if_match_matches(etag, etag));
+        assert!(if_match_matches("*", etag));
+        assert!(if_match_matches("\"other\", \"0123456789abcdef\"", etag));
+        assert!(!if_match_matches("\"other\"", etag));
+        // Weak tags never match, and neither do unquoted ones
+        assert!(!if_match_matches("W/\"0123456789abcdef\"", etag));
+        assert!(!if_match_matches("0123456789abcdef", etag));
+    }
 }
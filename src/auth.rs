@@ -0,0 +1,410 @@
+use anyhow::{bail, Context};
+use config::{Config, File, FileFormat};
+use log::{info, warn};
+use serde::Deserialize;
+use std::fmt;
+use tide::{
+    http::{headers, Method},
+    Middleware, Next, Request, Response, StatusCode,
+};
+
+/// What an API token is allowed to do
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Read config and status, but not change anything
+    Read,
+    /// Everything: change the config, run macros, drive with the virtual
+    /// joystick, etc.
+    Control,
+}
+
+/// A bearer token that API clients can authenticate with
+#[derive(Clone, Deserialize)]
+pub struct ApiToken {
+    /// Who the token belongs to, for the logs and config history
+    pub name: String,
+    pub token: String,
+    pub scope: Scope,
+}
+
+// Keep tokens out of the logs
+impl fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiToken")
+            .field("name", &self.name)
+            .field("scope", &self.scope)
+            .finish()
+    }
+}
+
+/// Contents of the secrets file
+#[derive(Debug, Deserialize)]
+struct Secrets {
+    #[serde(default)]
+    tokens: Vec<ApiToken>,
+}
+
+/// Load API tokens from a secrets file, which looks like:
+///
+/// ```toml
+/// [[tokens]]
+/// name = "laptop"
+/// token = "<long random string>"
+/// scope = "control"
+/// ```
+pub fn load_tokens(path: &str) -> anyhow::Result<Vec<ApiToken>> {
+    info!("Reading API tokens from {}", path);
+    let mut file = Config::new();
+    file.merge(File::new(path, FileFormat::Toml))
+        .with_context(|| format!("Reading secrets file {}", path))?;
+    let secrets: Secrets = file
+        .try_into()
+        .with_context(|| format!("Reading secrets file {}", path))?;
+
+    for (i, token) in secrets.tokens.iter().enumerate() {
+        if token.token.is_empty() {
+            bail!("{}: tokens[{}] ({}) is empty", path, i, token.name);
+        }
+        if secrets.tokens[..i]
+            .iter()
+            .any(|other| other.token == token.token)
+        {
+            bail!("{}: tokens[{}] ({}) is a duplicate", path, i, token.name);
+        }
+    }
+    Ok(secrets.tokens)
+}
+
+/// The client that made a request, as identified by its token. Attached to
+/// every authenticated request.
+#[derive(Clone, Debug)]
+pub struct ApiClient {
+    /// Name of the token the client used
+    pub name: String,
+}
+
+/// Middleware that requires every request to have a valid bearer token, with
+/// a scope that allows the request. The token is given in an
+/// `Authorization: Bearer <token>` header, or for WebSockets (which can't set
+/// headers from a browser) as a [TOKEN_PROTOCOL_PREFIX] subprotocol. Reading
+/// (GET) needs [Scope::Read], and anything else needs [Scope::Control]. The
+/// virtual joystick page is static, so it's served to anyone, and it asks for
+/// a token itself.
+///
+/// Missing or unknown tokens get a 401, and tokens without the right scope
+/// get a 403. Both are logged.
+#[derive(Debug)]
+pub struct Authenticator {
+    tokens: Vec<ApiToken>,
+}
+
+impl Authenticator {
+    pub fn new(tokens: Vec<ApiToken>) -> Self {
+        Self { tokens }
+    }
+
+    /// Find the token that a request was made with, if it's a known one.
+    /// Every token is compared in full, so the response time doesn't leak
+    /// how much of a token was right.
+    fn find_token(&self, given: &str) -> Option<&ApiToken> {
+        let mut found = None;
+        for token in &self.tokens {
+            if constant_time_eq(&token.token, given) {
+                found = Some(token);
+            }
+        }
+        found
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Authenticator {
+    async fn handle(
+        &self,
+        mut req: Request<State>,
+        next: Next<'_, State>,
+    ) -> tide::Result {
+        if req.url().path() == "/joystick" && req.method() == Method::Get {
+            return Ok(next.run(req).await);
+        }
+        let required = required_scope(&req);
+        let token = match request_token(&req) {
+            None => {
+                return Ok(reject(
+                    &req,
+                    StatusCode::Unauthorized,
+                    "Missing bearer token",
+                    "Bearer realm=\"robot\"",
+                ))
+            }
+            Some(token) => self.find_token(&token),
+        };
+        let token = match token {
+            None => {
+                return Ok(reject(
+                    &req,
+                    StatusCode::Unauthorized,
+                    "Invalid bearer token",
+                    "Bearer realm=\"robot\", error=\"invalid_token\"",
+                ))
+            }
+            Some(token) => token,
+        };
+        if token.scope < required {
+            return Ok(reject(
+                &req,
+                StatusCode::Forbidden,
+                &format!(
+                    "Token {:?} has {:?} scope, but {:?} is required",
+                    token.name, token.scope, required
+                ),
+                "Bearer realm=\"robot\", error=\"insufficient_scope\"",
+            ));
+        }
+
+        req.set_ext(ApiClient {
+            name: token.name.clone(),
+        });
+        Ok(next.run(req).await)
+    }
+}
+
+/// Get the scope needed to make a request
+fn required_scope<State>(req: &Request<State>) -> Scope {
+    // The virtual joystick socket drives the robot, even though it's opened
+    // with a GET
+    if req.url().path() == "/joystick/ws" {
+        return Scope::Control;
+    }
+    match req.method() {
+        Method::Get | Method::Head | Method::Options => Scope::Read,
+        _ => Scope::Control,
+    }
+}
+
+/// Get the token that a request was made with, from either the
+/// `Authorization` header or the `Sec-WebSocket-Protocol` header
+fn request_token<State>(req: &Request<State>) -> Option<String> {
+    if let Some(value) = req.header(headers::AUTHORIZATION) {
+        let value = value.last().as_str();
+        return match value.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                Some(token.trim().to_owned())
+            }
+            _ => None,
+        };
+    }
+    req.header("Sec-WebSocket-Protocol")?
+        .iter()
+        .flat_map(|value| value.as_str().split(','))
+        .find_map(|protocol| {
+            protocol.trim().strip_prefix(TOKEN_PROTOCOL_PREFIX)
+        })
+        .and_then(decode_hex)
+}
+
+/// Prefix of the WebSocket subprotocol that carries a token, hex-encoded
+/// because subprotocols can't contain most punctuation. The URL would be
+/// simpler, but URLs end up in logs.
+pub const TOKEN_PROTOCOL_PREFIX: &str = "access-token.";
+
+/// Decode a hex string, if it's valid hex and UTF-8
+fn decode_hex(hex: &str) -> Option<String> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Log a failed request and build an error response for it
+fn reject<State>(
+    req: &Request<State>,
+    status: StatusCode,
+    message: &str,
+    challenge: &str,
+) -> Response {
+    warn!(
+        "Rejected API request {} {} from {}: {}",
+        req.method(),
+        req.url().path(),
+        req.remote().unwrap_or("unknown"),
+        message
+    );
+    Response::builder(status)
+        .header(headers::WWW_AUTHENTICATE, challenge)
+        .body(message)
+        .build()
+}
+
+/// Compare two strings without bailing out at the first difference
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+    use std::fs;
+    use tide::http::{
+        Method, Request as HttpRequest, Response as HttpResponse,
+    };
+
+    fn token(name: &str, token: &str, scope: Scope) -> ApiToken {
+        ApiToken {
+            name: name.into(),
+            token: token.into(),
+            scope,
+        }
+    }
+
+    /// Send a request through an authenticated server, and get the status.
+    /// Every route just responds with 200.
+    fn status(
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> StatusCode {
+        let mut app = tide::new();
+        app.with(Authenticator::new(vec![
+            token("control", "control-token", Scope::Control),
+            token("read", "read-token", Scope::Read),
+        ]));
+        for route in ["/config", "/joystick", "/joystick/ws"] {
+            app.at(route)
+                .get(|_| async { Ok("") })
+                .post(|_| async { Ok("") });
+        }
+        let url = format!("http://robot{}", path);
+        let mut req = HttpRequest::new(method, url.as_str());
+        for (name, value) in headers {
+            req.insert_header(*name, *value);
+        }
+        let res: HttpResponse = task::block_on(app.respond(req)).unwrap();
+        res.status()
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let control = [("Authorization", "Bearer control-token")];
+        let read = [("Authorization", "bearer read-token")];
+        assert_eq!(status(Method::Get, "/config", &control), StatusCode::Ok);
+        assert_eq!(status(Method::Post, "/config", &control), StatusCode::Ok);
+        assert_eq!(status(Method::Get, "/config", &read), StatusCode::Ok);
+        assert_eq!(
+            status(Method::Post, "/config", &read),
+            StatusCode::Forbidden
+        );
+    }
+
+    #[test]
+    fn test_invalid_token() {
+        for headers in [
+            &[][..],
+            &[("Authorization", "Bearer control-tokem")],
+            &[("Authorization", "Bearer control")],
+            &[("Authorization", "Basic control-token")],
+        ] {
+            assert_eq!(
+                status(Method::Get, "/config", headers),
+                StatusCode::Unauthorized,
+                "{:?}",
+                headers
+            );
+        }
+        // Tokens in the URL aren't accepted
+        assert_eq!(
+            status(Method::Get, "/config?access_token=control-token", &[]),
+            StatusCode::Unauthorized
+        );
+    }
+
+    /// The joystick page is public, but its socket needs a control token,
+    /// sent as a subprotocol
+    #[test]
+    fn test_joystick() {
+        assert_eq!(status(Method::Get, "/joystick", &[]), StatusCode::Ok);
+        assert_eq!(
+            status(Method::Get, "/joystick/ws", &[]),
+            StatusCode::Unauthorized
+        );
+        let protocol = |token: &str| {
+            let hex: String =
+                token.bytes().map(|byte| format!("{:02x}", byte)).collect();
+            format!("robot-joystick, {}{}", TOKEN_PROTOCOL_PREFIX, hex)
+        };
+        assert_eq!(
+            status(
+                Method::Get,
+                "/joystick/ws",
+                &[("Sec-WebSocket-Protocol", &protocol("control-token"))]
+            ),
+            StatusCode::Ok
+        );
+        assert_eq!(
+            status(
+                Method::Get,
+                "/joystick/ws",
+                &[("Sec-WebSocket-Protocol", &protocol("read-token"))]
+            ),
+            StatusCode::Forbidden
+        );
+    }
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex("6869").as_deref(), Some("hi"));
+        assert_eq!(decode_hex("").as_deref(), Some(""));
+        assert_eq!(decode_hex("686"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("ff"), None);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq("token", "token"));
+        assert!(!constant_time_eq("token", "tokem"));
+        assert!(!constant_time_eq("token", "token2"));
+        assert!(!constant_time_eq("token", ""));
+    }
+
+    #[test]
+    fn test_load_tokens() {
+        let dir = std::env::temp_dir()
+            .join(format!("robot-auth-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("secrets.toml");
+        let path_str = path.to_str().unwrap();
+        let load = |content: &str| {
+            fs::write(&path, content).unwrap();
+            load_tokens(path_str)
+        };
+
+        let tokens = load(
+            "[[tokens]]\nname = \"a\"\ntoken = \"1\"\nscope = \"read\"\n\
+            [[tokens]]\nname = \"b\"\ntoken = \"2\"\nscope = \"control\"\n",
+        )
+        .unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[1].scope, Scope::Control);
+        assert!(load(
+            "[[tokens]]\nname = \"a\"\ntoken = \"\"\nscope = \"read\"\n"
+        )
+        .is_err());
+        assert!(load(
+            "[[tokens]]\nname = \"a\"\ntoken = \"1\"\nscope = \"read\"\n\
+            [[tokens]]\nname = \"b\"\ntoken = \"1\"\nscope = \"control\"\n",
+        )
+        .is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+use crate::persist;
+use anyhow::Context;
+use gilrs::Axis;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Every axis that we know how to calibrate
+const CALIBRATED_AXES: &[Axis] = &[
+    Axis::LeftStickX,
+    Axis::LeftStickY,
+    Axis::LeftZ,
+    Axis::RightStickX,
+    Axis::RightStickY,
+    Axis::RightZ,
+];
+
+/// Calibration for a single axis on a gamepad. Raw values are mapped so that
+/// `center` becomes 0, `min` becomes -1 and `max` becomes 1.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct AxisCalibration {
+    /// Raw value of the axis at rest
+    pub center: f32,
+    /// Lowest raw value the axis can reach
+    pub min: f32,
+    /// Highest raw value the axis can reach
+    pub max: f32,
+}
+
+impl AxisCalibration {
+    /// Map a raw axis value into [-1, 1] using this calibration
+    pub fn apply(self, raw_value: f32) -> f32 {
+        let value = if raw_value >= self.center {
+            (raw_value - self.center) / (self.max - self.center)
+        } else {
+            (raw_value - self.center) / (self.center - self.min)
+        };
+        value.clamp(-1.0, 1.0)
+    }
+
+    /// Is this calibration usable? If the axis was never moved in one
+    /// direction during calibration, we can't map values on that side.
+    fn is_valid(self) -> bool {
+        self.min < self.center && self.center < self.max
+    }
+}
+
+/// Calibration for all axes on one gamepad
+pub type GamepadCalibration = HashMap<Axis, AxisCalibration>;
+
+/// An in-progress calibration of one gamepad. The first sample of each axis
+/// is taken as its center, so the sticks should be at rest when calibration
+/// starts. After that, the user should move each stick through its full range.
+#[derive(Clone, Debug, Serialize)]
+pub struct CalibrationSession {
+    /// UUID of the gamepad being calibrated
+    pub uuid: String,
+    /// Values recorded so far
+    pub axes: GamepadCalibration,
+}
+
+/// All known gamepad calibrations, keyed by gamepad UUID. These are persisted
+/// to a JSON file, so they survive restarts.
+#[derive(Debug, Serialize)]
+pub struct CalibrationStore {
+    #[serde(skip)]
+    path: PathBuf,
+    profiles: HashMap<String, GamepadCalibration>,
+    session: Option<CalibrationSession>,
+}
+
+impl CalibrationStore {
+    /// Load calibrations from the given file. If the file doesn't exist yet,
+    /// start with no calibrations.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let profiles = if path.exists() {
+            info!("Reading gamepad calibration from {}", path.display());
+            let content = fs::read_to_string(&path)?;
+            serde_json::from_str(&content).with_context(|| {
+                format!("Parsing calibration file {}", path.display())
+            })?
+        } else {
+            info!(
+                "No gamepad calibration file at {}, starting fresh",
+                path.display()
+            );
+            HashMap::new()
+        };
+        Ok(Self {
+            path,
+            profiles,
+            session: None,
+        })
+    }
+
+    /// Serialize all calibrations, ready to be written to the calibration
+    /// file
+    fn pending_save(&self) -> anyhow::Result<PendingSave> {
+        Ok(PendingSave {
+            path: self.path.clone(),
+            content: serde_json::to_string_pretty(&self.profiles)?,
+        })
+    }
+
+    /// Get the calibration for a single axis on a gamepad, if we have one
+    pub fn get(&self, uuid: &str, axis: Axis) -> Option<AxisCalibration> {
+        self.profiles.get(uuid)?.get(&axis).copied()
+    }
+
+    /// Start calibrating the gamepad with the given UUID. This replaces any
+    /// calibration that was already in progress.
+    pub fn start(&mut self, uuid: String) -> &CalibrationSession {
+        info!("Starting calibration for gamepad {}", uuid);
+        self.session.insert(CalibrationSession {
+            uuid,
+            axes: HashMap::new(),
+        })
+    }
+
+    /// Record the current axis values from a gamepad, if that gamepad is being
+    /// calibrated. `read_axis` should return the raw value of an axis.
+    pub fn record(
+        &mut self,
+        uuid: &str,
+        read_axis: impl Fn(Axis) -> Option<f32>,
+    ) {
+        let session = match &mut self.session {
+            Some(session) if session.uuid == uuid => session,
+            _ => return,
+        };
+
+        for &axis in CALIBRATED_AXES {
+            if let Some(value) = read_axis(axis) {
+                let calibration =
+                    session.axes.entry(axis).or_insert(AxisCalibration {
+                        center: value,
+                        min: value,
+                        max: value,
+                    });
+                calibration.min = calibration.min.min(value);
+                calibration.max = calibration.max.max(value);
+            }
+        }
+    }
+
+    /// Finish the in-progress calibration and store the results. Axes that
+    /// weren't moved through their full range are discarded. Returns the new
+    /// calibration and the save that persists it, or an error if no
+    /// calibration is in progress.
+    pub fn finish(
+        &mut self,
+    ) -> anyhow::Result<(GamepadCalibration, PendingSave)> {
+        let session =
+            self.session.take().context("No calibration in progress")?;
+
+        let mut calibration = session.axes;
+        calibration.retain(|axis, axis_calibration| {
+            let valid = axis_calibration.is_valid();
+            if !valid {
+                warn!(
+                    "Discarding calibration for {:?}, it wasn't moved through \
+                    its full range",
+                    axis
+                );
+            }
+            valid
+        });
+
+        info!(
+            "Finished calibration for gamepad {}: {:?}",
+            session.uuid, calibration
+        );
+        self.profiles.insert(session.uuid, calibration.clone());
+        Ok((calibration, self.pending_save()?))
+    }
+
+    /// Delete the calibration for a gamepad, so its raw values will be used.
+    /// Returns the removed calibration and the save that persists the
+    /// removal, if there was one.
+    pub fn reset(
+        &mut self,
+        uuid: &str,
+    ) -> anyhow::Result<Option<(GamepadCalibration, PendingSave)>> {
+        match self.profiles.remove(uuid) {
+            None => Ok(None),
+            Some(removed) => {
+                info!("Reset calibration for gamepad {}", uuid);
+                Ok(Some((removed, self.pending_save()?)))
+            }
+        }
+    }
+}
+
+/// Calibrations that still have to be written to the calibration file. This
+/// is separate from the store, so the file can be written without holding the
+/// store's lock.
+#[must_use]
+pub struct PendingSave {
+    path: PathBuf,
+    content: String,
+}
+
+impl PendingSave {
+    /// Write the calibrations to the calibration file
+    pub fn save(self) -> anyhow::Result<()> {
+        persist::write_atomic(&self.path, &self.content).with_context(|| {
+            format!("Writing calibration file {}", self.path.display())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CALIBRATION: AxisCalibration = AxisCalibration {
+        center: 0.1,
+        min: -0.5,
+        max: 0.9,
+    };
+
+    #[test]
+    fn test_apply() {
+        assert_eq!(CALIBRATION.apply(0.1), 0.0);
+        assert_eq!(CALIBRATION.apply(-0.5), -1.0);
+        assert_eq!(CALIBRATION.apply(0.9), 1.0);
+        // Each side is scaled separately, since the center is off to one side
+        assert!((CALIBRATION.apply(0.5) - 0.5).abs() < 1e-6);
+        assert!((CALIBRATION.apply(-0.2) - -0.5).abs() < 1e-6);
+    }
+
+    /// Values past the calibrated range still map into [-1, 1]
+    #[test]
+    fn test_apply_clamped() {
+        assert_eq!(CALIBRATION.apply(1.0), 1.0);
+        assert_eq!(CALIBRATION.apply(-1.0), -1.0);
+    }
+
+    /// Axes that weren't moved both ways are left out of the calibration
+    #[test]
+    fn test_finish_discards_invalid_axes() {
+        let mut store =
+            CalibrationStore::load("/nonexistent/calibration.json").unwrap();
+        store.start("pad".into());
+        for (x, y) in [(0.0, 0.0), (-0.8, 0.5), (0.9, 0.7)] {
+            store.record("pad", |axis| match axis {
+                Axis::LeftStickX => Some(x),
+                Axis::LeftStickY => Some(y),
+                _ => None,
+            });
+        }
+        let (calibration, _) = store.finish().unwrap();
+        assert_eq!(calibration.len(), 1);
+        assert!(store.get("pad", Axis::LeftStickX).is_some());
+        assert!(store.get("pad", Axis::LeftStickY).is_none());
+        assert!(store.finish().is_err());
+    }
+
+    /// Other gamepads are ignored while one is being calibrated
+    #[test]
+    fn test_record_other_gamepad() {
+        let mut store =
+            CalibrationStore::load("/nonexistent/calibration.json").unwrap();
+        store.start("pad".into());
+        store.record("other", |_| Some(0.5));
+        let (calibration, _) = store.finish().unwrap();
+        assert!(calibration.is_empty());
+    }
+}
@@ -0,0 +1,405 @@
+use crate::{
+    config::{ConfigSources, DriveMotorLocation, RobotConfig},
+    input, migrate,
+    motors::{self, MotorChannel, MotorController},
+    schema, validation,
+};
+use anyhow::{bail, Context};
+use linux_embedded_hal::i2cdev::{core::I2CDevice, linux::LinuxI2CDevice};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::{
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+const DEFAULT_CONFIG_PATH: &str = "./config/default.toml";
+/// Robot-specific config overrides, layered on top of the base config if the
+/// file exists
+const DEFAULT_OVERRIDE_PATH: &str = "./config/local.toml";
+
+/// Command line arguments
+#[derive(Debug, StructOpt)]
+#[structopt(name = "robot", about = "Drive the robot with a gamepad")]
+pub struct Args {
+    /// Path to the base config file
+    #[structopt(
+        short,
+        long,
+        global = true,
+        default_value = DEFAULT_CONFIG_PATH
+    )]
+    pub config: String,
+
+    /// Config file to layer on top of the base config. Can be given multiple
+    /// times. ./config/local.toml is always used first, if it exists.
+    #[structopt(long = "override", global = true, number_of_values = 1)]
+    pub overrides: Vec<String>,
+
+    /// Override a single config value, e.g. `--set drive.simulate=true`. Can
+    /// be given multiple times.
+    #[structopt(
+        long = "set",
+        global = true,
+        number_of_values = 1,
+        parse(try_from_str = parse_set)
+    )]
+    pub sets: Vec<(String, String)>,
+
+    /// Config profile to activate (shorthand for `--set profile=<name>`)
+    #[structopt(long, global = true)]
+    pub profile: Option<String>,
+
+    /// Log filter, e.g. `debug` or `info,robot::input=trace`. Overrides
+    /// RUST_LOG. Defaults to `info`.
+    #[structopt(long, global = true)]
+    pub log_level: Option<String>,
+
+    /// Simulate the drive motors instead of touching the hardware
+    /// (shorthand for `--set drive.simulate=true`)
+    #[structopt(long, global = true)]
+    pub dry_run: bool,
+
+    /// Address for the API to listen on, e.g. 127.0.0.1:8000 (shorthand for
+    /// `--set api.host=<host>`)
+    #[structopt(long, global = true)]
+    pub host: Option<String>,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Run the robot (the default)
+    Run,
+    /// Load and validate the config, and print any problems with it
+    CheckConfig,
+    /// Print the effective config, with the source of each value
+    PrintConfig,
+    /// Rewrite outdated config files in the current format
+    MigrateConfig,
+    /// Print a JSON Schema for the config
+    PrintSchema,
+    /// Spin each drive motor forward, then backward, to check the wiring
+    MotorTest {
+        /// Only test this motor, e.g. front_left
+        #[structopt(parse(try_from_str = parse_location))]
+        motor: Option<DriveMotorLocation>,
+        /// Speed to run each motor at, 0 to 1
+        #[structopt(long, default_value = "0.3")]
+        speed: f32,
+        /// How long to run each motor in each direction, in milliseconds
+        #[structopt(long, default_value = "1000")]
+        duration_ms: u64,
+    },
+    /// List all connected gamepads, with the UUIDs used to select them
+    ListGamepads,
+    /// Look for devices on the I2C bus
+    I2cScan,
+}
+
+impl Args {
+    /// Get where to load config from, including any overrides given as
+    /// options
+    pub fn config_sources(&self) -> ConfigSources {
+        let mut overrides = vec![DEFAULT_OVERRIDE_PATH.to_owned()];
+        overrides.extend(self.overrides.iter().cloned());
+
+        let mut sets = self.sets.clone();
+        if let Some(profile) = &self.profile {
+            sets.push(("profile".into(), profile.clone()));
+        }
+        if self.dry_run {
+            sets.push(("drive.simulate".into(), "true".into()));
+        }
+        if let Some(host) = &self.host {
+            sets.push(("api.host".into(), host.clone()));
+        }
+
+        ConfigSources {
+            path: self.config.clone(),
+            overrides,
+            sets,
+        }
+    }
+}
+
+/// Parse a `key=value` config override
+fn parse_set(set: &str) -> Result<(String, String), String> {
+    set.split_once('=')
+        .map(|(key, value)| (key.into(), value.into()))
+        .ok_or_else(|| format!("Expected key=value, got {:?}", set))
+}
+
+/// Parse a drive motor location by its config name, e.g. `front_left`
+fn parse_location(name: &str) -> Result<DriveMotorLocation, String> {
+    DriveMotorLocation::ALL
+        .iter()
+        .copied()
+        .find(|&location| validation::location_name(location) == name)
+        .ok_or_else(|| {
+            let names: Vec<_> = DriveMotorLocation::ALL
+                .iter()
+                .map(|&location| validation::location_name(location))
+                .collect();
+            format!("Expected one of {}, got {:?}", names.join(", "), name)
+        })
+}
+
+/// Print an error from a command. Returns the exit code for the process.
+fn fail(err: anyhow::Error) -> i32 {
+    eprintln!("error: {:#}", err);
+    1
+}
+
+/// Print the effective config, merged from all sources, with the source of
+/// each value. Returns the exit code for the process.
+pub fn print_config(sources: &ConfigSources) -> i32 {
+    match RobotConfig::load_with_sources(sources) {
+        Ok(values) => {
+            for value in values {
+                println!("{} = {} # {}", value.key, value.value, value.source);
+            }
+            0
+        }
+        Err(err) => fail(err),
+    }
+}
+
+/// Load and validate the config, and print any problems with it. Returns the
+/// exit code for the process.
+pub fn check_config(sources: &ConfigSources) -> i32 {
+    let config = match RobotConfig::load(sources) {
+        Ok(config) => config,
+        Err(err) => return fail(err),
+    };
+    let problems = config.validate();
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    if problems.has_errors() {
+        1
+    } else {
+        if problems.is_empty() {
+            println!("{} is valid", sources.path);
+        }
+        0
+    }
+}
+
+/// Rewrite every outdated config file in the current format, so it doesn't
+/// need to be migrated on each load. Returns the exit code for the process.
+pub fn migrate_config(sources: &ConfigSources) -> i32 {
+    // Load everything first, so we know the migrated config is usable, and
+    // where to put backups
+    let config = match RobotConfig::load(sources) {
+        Ok(config) => config,
+        Err(err) => return fail(err),
+    };
+
+    // Check the base file's version before it's migrated, since override
+    // files without a version are at the same one
+    let base_version = match migrate::file_version(&sources.path) {
+        Ok(version) => version,
+        Err(err) => return fail(err),
+    };
+    let mut exit_code = 0;
+    for path in sources.paths().filter(|path| Path::new(path).exists()) {
+        // Only the base file gets a version
+        let file_base_version = (path != sources.path).then_some(base_version);
+        match migrate::migrate_file(
+            path,
+            file_base_version,
+            &config.api.backup_dir,
+            config.api.max_backups,
+        ) {
+            Ok(true) => println!("Migrated {}", path),
+            Ok(false) => println!("{} is up to date", path),
+            Err(err) => exit_code = fail(err),
+        }
+    }
+    exit_code
+}
+
+/// Print a JSON Schema for the config. Returns the exit code for the process.
+pub fn print_schema() -> i32 {
+    match serde_json::to_string_pretty(&schema::config_schema()) {
+        Ok(schema) => {
+            println!("{}", schema);
+            0
+        }
+        Err(err) => fail(err.into()),
+    }
+}
+
+/// Run drive motors one at a time, forward then backward, so you can check
+/// that each location is mapped to the right channel and spins the right way.
+/// Returns the exit code for the process.
+pub fn motor_test(
+    sources: &ConfigSources,
+    motor: Option<DriveMotorLocation>,
+    speed: f32,
+    duration: Duration,
+) -> i32 {
+    match run_motor_test(sources, motor, speed, duration) {
+        Ok(()) => 0,
+        Err(err) => fail(err),
+    }
+}
+
+fn run_motor_test(
+    sources: &ConfigSources,
+    motor: Option<DriveMotorLocation>,
+    speed: f32,
+    duration: Duration,
+) -> anyhow::Result<()> {
+    let config = RobotConfig::load(sources)?;
+    let config = config.resolve_profile()?;
+    let speed = speed.clamp(0.0, 1.0);
+
+    // Catch Ctrl-C, so we get a chance to stop the motors
+    let interrupted = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&interrupted))
+            .context("Registering signal handler")?;
+    }
+
+    let locations = match motor {
+        Some(motor) => vec![motor],
+        None => DriveMotorLocation::ALL.to_vec(),
+    };
+    let mut channels = Vec::new();
+    for &location in &locations {
+        let name = validation::location_name(location);
+        let channel =
+            *config.drive.motors.get(&location).with_context(|| {
+                format!("No motor channel mapped to {}", name)
+            })?;
+        channels.push((name, channel));
+    }
+    let mut drive_motors = StopMotors {
+        motors: motors::init_drive_motors(&config)?,
+        channels: channels.iter().map(|&(_, channel)| channel).collect(),
+    };
+
+    for (name, channel) in channels {
+        for (direction, speed) in [("forward", speed), ("backward", -speed)] {
+            println!("{} ({:?}): {} at {}", name, channel, direction, speed);
+            drive_motors
+                .motors
+                .set_speed(channel, speed)
+                .with_context(|| format!("Running {}", name))?;
+            let end = Instant::now() + duration;
+            while Instant::now() < end {
+                if interrupted.load(Ordering::Relaxed) {
+                    bail!("Interrupted");
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        drive_motors
+            .motors
+            .set_speed(channel, 0.0)
+            .with_context(|| format!("Stopping {}", name))?;
+    }
+    Ok(())
+}
+
+/// Motors under test, which are all stopped when this is dropped, so a
+/// failed or interrupted test doesn't leave one running
+struct StopMotors {
+    motors: Box<dyn MotorController>,
+    channels: Vec<MotorChannel>,
+}
+
+impl Drop for StopMotors {
+    fn drop(&mut self) {
+        for &channel in &self.channels {
+            if let Err(err) = self.motors.set_speed(channel, 0.0) {
+                eprintln!("error: Stopping {:?}: {:#}", channel, err);
+            }
+        }
+    }
+}
+
+/// Print every connected gamepad, with the index and UUID that a gamepad
+/// selector can match it by. Returns the exit code for the process.
+pub fn list_gamepads(sources: &ConfigSources) -> i32 {
+    let gamepads = RobotConfig::load(sources)
+        .and_then(|config| input::connected_gamepads(&config.input));
+    match gamepads {
+        Ok(gamepads) if gamepads.is_empty() => {
+            println!("No gamepads connected");
+            0
+        }
+        Ok(gamepads) => {
+            for (index, (_, info)) in gamepads.iter().enumerate() {
+                println!("{}: {} (uuid={})", index, info.name, info.uuid);
+            }
+            0
+        }
+        Err(err) => fail(err),
+    }
+}
+
+/// Probe every address on the I2C bus, and print the ones that respond.
+/// Returns the exit code for the process.
+pub fn i2c_scan(sources: &ConfigSources) -> i32 {
+    match run_i2c_scan(sources) {
+        Ok(()) => 0,
+        Err(err) => fail(err),
+    }
+}
+
+fn run_i2c_scan(sources: &ConfigSources) -> anyhow::Result<()> {
+    let config = RobotConfig::load(sources)?;
+    let path = &config.general.i2c_device_path;
+    println!("Scanning {}", path);
+
+    let mut found = 0;
+    let mut found_hat = false;
+    for address in validation::I2C_ADDRESSES {
+        let status = match LinuxI2CDevice::new(path, address.into()) {
+            // Reading a byte is the same probe i2cdetect uses for most
+            // addresses. Nothing answers at an empty address.
+            Ok(mut device) => match device.smbus_read_byte() {
+                Ok(_) => "found",
+                Err(_) => continue,
+            },
+            // A kernel driver has claimed this address, so there's
+            // definitely something there
+            Err(err) => match io::Error::from(err) {
+                err if err.kind() == io::ErrorKind::ResourceBusy => {
+                    "in use by a kernel driver"
+                }
+                err => {
+                    return Err(err).with_context(|| {
+                        format!("Opening I2C device {}", path)
+                    })
+                }
+            },
+        };
+        found += 1;
+        if address == config.drive.i2c_address {
+            found_hat = true;
+            println!("0x{:02x}: {} (drive motor HAT)", address, status);
+        } else {
+            println!("0x{:02x}: {}", address, status);
+        }
+    }
+    println!("Found {} device(s)", found);
+    if !found_hat {
+        println!(
+            "Drive motor HAT not found at 0x{:02x} (drive.i2c_address)",
+            config.drive.i2c_address
+        );
+    }
+    Ok(())
+}
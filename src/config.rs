@@ -1,11 +1,127 @@
-use crate::{input::InputAxis, motors::MotorChannel};
-use config::{Config, File};
+use crate::{
+    input::{
+        ControlConfig, GamepadSelector, InputMapping, KeyboardConfig,
+        ManualMapping, NetworkConfig, RacingMapping, TankMapping,
+    },
+    macros::MacroConfig,
+    migrate,
+    motors::MotorChannel,
+    profiles::Profile,
+    schema::ButtonName,
+    speed::SpeedConfig,
+};
+use anyhow::Context;
+use config::{Config, Environment};
+use gilrs::Button;
 use log::info;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+/// Environment variables with this prefix override config values. Nested
+/// keys are separated by [ENV_SEPARATOR], e.g. `ROBOT_API__HOST`.
+const ENV_PREFIX: &str = "ROBOT";
+const ENV_SEPARATOR: &str = "__";
+
+/// Where the robot config is loaded from. Each source is layered on top of
+/// the ones before it, so later sources take priority:
+///
+/// 1. The base config file
+/// 2. Override files, e.g. for settings specific to one robot
+/// 3. `ROBOT_`-prefixed environment variables
+/// 4. `key=value` overrides from the command line
+#[derive(Clone, Debug)]
+pub struct ConfigSources {
+    /// The base config file
+    pub path: String,
+    /// Files layered on top of the base config. Files that don't exist are
+    /// skipped.
+    pub overrides: Vec<String>,
+    /// Individual values, as `(key, value)`, e.g.
+    /// `("api.host", "127.0.0.1:8000")`
+    pub sets: Vec<(String, String)>,
+}
+
+impl ConfigSources {
+    /// Get the paths of all config files, whether or not they exist
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.path.as_str())
+            .chain(self.overrides.iter().map(String::as_str))
+    }
+
+    /// Load each source into its own config, labelled with where it came
+    /// from, in order of increasing priority. Outdated config files are
+    /// migrated to the current version.
+    fn layers(&self) -> anyhow::Result<Vec<(String, Config)>> {
+        let mut layers = Vec::new();
+
+        layers.push((self.path.clone(), migrate::load_file(&self.path, None)?));
+
+        // Override files are at the base file's version, unless they say
+        // otherwise
+        let base_version = migrate::file_version(&self.path)?;
+        for path in &self.overrides {
+            if Path::new(path).exists() {
+                info!("Reading config overrides from {}", path);
+                layers.push((
+                    path.clone(),
+                    migrate::load_file(path, Some(base_version))?,
+                ));
+            }
+        }
+
+        let mut env = Config::new();
+        env.merge(
+            Environment::with_prefix(ENV_PREFIX).separator(ENV_SEPARATOR),
+        )?;
+        layers.push(("environment".into(), env));
+
+        let mut sets = Config::new();
+        for (key, value) in &self.sets {
+            sets.set(key, value.as_str())
+                .with_context(|| format!("Setting {}={}", key, value))?;
+        }
+        layers.push(("--set".into(), sets));
+
+        Ok(layers)
+    }
+
+    /// Find which source set each value, by dotted key, e.g. `api.host`.
+    /// Later sources win, so this is where each value in the effective config
+    /// came from. Keys that aren't here come from the defaults.
+    pub fn key_sources(&self) -> anyhow::Result<HashMap<String, String>> {
+        let mut key_sources = HashMap::new();
+        for (name, layer) in self.layers()? {
+            let mut keys = Vec::new();
+            flatten("", layer.try_into()?, &mut keys);
+            for (key, _) in keys {
+                key_sources.insert(key, name.clone());
+            }
+        }
+        Ok(key_sources)
+    }
+}
+
+/// One value in the effective config, with where it came from
+#[derive(Clone, Debug)]
+pub struct ConfigValue {
+    /// Dotted path to the value, e.g. `api.host`
+    pub key: String,
+    pub value: Value,
+    /// The source that set this value, or "default" if none of them did
+    pub source: String,
+}
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct RobotConfig {
+    /// Config format version. Older configs are migrated to the current
+    /// version when they're loaded, see [crate::migrate].
+    #[serde(default = "default_version")]
+    pub version: u64,
     /// User input configuration
     pub input: InputConfig,
     /// Robot drive system configuration
@@ -14,70 +130,251 @@ pub struct RobotConfig {
     pub api: ApiConfig,
     /// Stuff that doesn't fall under other categories
     pub general: GeneralConfig,
+    /// Named sets of overrides for [Self::input] and [Self::drive], by name
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    /// The active profile, if any
+    #[serde(default)]
+    pub profile: Option<String>,
+}
+
+/// Configs are only deserialized once they've been migrated, so they're
+/// always the current version
+fn default_version() -> u64 {
+    migrate::CONFIG_VERSION
 }
 
+/// The gamepad slot used when none is specified
+const DEFAULT_GAMEPAD_SLOT: &str = "driver";
+
 /// User input configuration, including button and axis mappings
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct InputConfig {
+    /// Named gamepad slots (e.g. "driver", "operator"). Each slot is bound to
+    /// the first connected gamepad (or other input device, such as the
+    /// keyboard) that matches one of its selectors, tried in order. Defaults
+    /// to a single "driver" slot that uses the first connected gamepad.
+    #[serde(default = "default_gamepads")]
+    pub gamepads: HashMap<String, Vec<GamepadSelector>>,
+    /// Extra SDL2 gamepad mappings, in the same format as
+    /// [gamecontrollerdb.txt](https://github.com/gabomdq/SDL_GameControllerDB).
+    /// Use these to fix gamepads whose axes/buttons come up wrong. These take
+    /// priority over the built-in mappings. Only loaded at startup.
+    #[serde(default)]
+    pub mappings: Vec<String>,
+    /// Path to a file of extra SDL2 gamepad mappings, one per line. Loaded in
+    /// addition to [Self::mappings], at startup only.
+    #[serde(default)]
+    pub mappings_path: Option<String>,
+    /// Terminal keyboard input, for driving without a gamepad
+    #[serde(default)]
+    pub keyboard: KeyboardConfig,
+    /// Virtual joysticks in a web browser (e.g. on a phone), connected via
+    /// the API
+    #[serde(default)]
+    pub network: NetworkConfig,
+    /// If set, record all raw input to this file, so it can be replayed
+    /// later. Only read at startup.
+    #[serde(default)]
+    pub record_path: Option<String>,
+    /// If set, replay input from a recording (see [Self::record_path])
+    /// instead of reading any real input devices. The robot shuts down when
+    /// the replay ends. Only read at startup.
+    #[serde(default)]
+    pub replay_path: Option<String>,
+    /// Instead of following the clock, advance the replay by this many
+    /// milliseconds on each loop. This makes a replay deterministic
+    /// (including axis filters), so it plays out the same way every time, but
+    /// it no longer runs in real time. Only read at startup.
+    #[serde(default)]
+    pub replay_step_ms: Option<u64>,
+    /// Path to the file where gamepad axis calibrations are stored
+    #[serde(default = "default_calibration_path")]
+    pub calibration_path: String,
+    /// The gamepad slot used to control the robot drive system, unless
+    /// [Self::control] lists multiple slots
+    #[serde(default = "default_gamepad_slot")]
+    pub drive_gamepad: String,
+    /// Sharing drive control between multiple gamepad slots, e.g. for an
+    /// instructor to take over from a student
+    #[serde(default)]
+    pub control: ControlConfig,
     /// Configuration for the inputs used to control the robot drive system
     pub drive: DriveInputMapping,
+    /// Speed gears, applied to the drive system regardless of mapping
+    #[serde(default)]
+    pub speed: SpeedConfig,
+    /// Canned drive maneuvers, which can be started from a gamepad button or
+    /// the API
+    #[serde(default)]
+    pub macros: MacroConfig,
+    /// Button on the controlling gamepad that switches to the next profile
+    /// (see [RobotConfig::profiles])
+    #[serde(default)]
+    #[schemars(with = "Option<ButtonName>")]
+    pub profile_button: Option<Button>,
+}
+
+fn default_gamepads() -> HashMap<String, Vec<GamepadSelector>> {
+    let mut gamepads = HashMap::new();
+    gamepads
+        .insert(DEFAULT_GAMEPAD_SLOT.into(), vec![GamepadSelector::Index(0)]);
+    gamepads
+}
+
+fn default_gamepad_slot() -> String {
+    DEFAULT_GAMEPAD_SLOT.into()
+}
+
+fn default_calibration_path() -> String {
+    "./calibration.json".into()
 }
 
 /// The mapping of inputs used to control the robot's drive system. There are
 /// multiple different drive input types, so each variant in this enum
-/// represents one mapping type.
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+/// represents one mapping type, registered under the name given by the `type`
+/// field in the config. To add a new mapping type, implement [InputMapping]
+/// for it and add a variant here.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum DriveInputMapping {
-    /// Tank drive, in which the two motors on one side of the robot (left or
-    /// right) run in sync. One axis controls the left motors and another
-    /// controls the right.
-    Tank {
-        left_motor_axis: InputAxis,
-        right_motor_axis: InputAxis,
-    },
-    /// Set motor speeds manually. All values are [-1, 1]. Useful to set motor
-    /// speeds from the HTTP API.
-    Manual {
-        front_left: f32,
-        front_right: f32,
-        back_left: f32,
-        back_right: f32,
-    },
+    Tank(TankMapping),
+    Racing(RacingMapping),
+    Manual(ManualMapping),
+}
+
+impl DriveInputMapping {
+    /// Get the input mapping implementation for this mapping type
+    pub fn mapping(&self) -> &dyn InputMapping {
+        match self {
+            Self::Tank(mapping) => mapping,
+            Self::Racing(mapping) => mapping,
+            Self::Manual(mapping) => mapping,
+        }
+    }
 }
 
 /// Robot drive system configuration, including motor mappings
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct DriveConfig {
     /// I2C address for the drive motor controller board
     pub i2c_address: u8,
 
+    /// Use a simulated motor controller instead of the real one, which just
+    /// logs motor speeds. Useful for running without hardware.
+    #[serde(default)]
+    pub simulate: bool,
+
     /// Mapping of motor positions as the drive train sees them (front-left,
     /// front-right, etc.) to how the motor controller sees them (motor 1,
-    /// motor 2, etc.). This _should_ always have an entry for each
-    /// [DriveMotor], but that isn't enforced. If one is missing, it will
-    /// trigger a warning at runtime.
+    /// motor 2, etc.). There must be exactly one entry for each
+    /// [DriveMotorLocation], and no two can share a channel. This is checked
+    /// by [RobotConfig::validate].
     pub motors: HashMap<DriveMotorLocation, MotorChannel>,
 }
 
 /// HTTP API configuration. The API allows users to read and write robot state
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct ApiConfig {
     /// IP and port to bind to, e.g. 127.0.0.1:8000. Use 0.0.0.0 to allow
     /// access on any host
     pub host: String,
+    /// Write config changes made through the API back to the base config
+    /// file, so they survive a restart. Only changed values are written, so
+    /// comments and formatting are kept. Values from other sources can't be
+    /// changed while this is on.
+    #[serde(default)]
+    pub persist: bool,
+    /// Directory where the config file is backed up before each persisted
+    /// change. Backups are named with a timestamp.
+    #[serde(default = "default_backup_dir")]
+    pub backup_dir: String,
+    /// Number of config backups to keep, at least 1. The oldest are deleted
+    /// first.
+    #[serde(default = "default_max_backups")]
+    pub max_backups: usize,
+    /// Number of config revisions to keep in the history, for auditing and
+    /// rolling back changes. Only read at startup.
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
+    /// If set, the config history is saved to this JSON file, so it survives
+    /// a restart. Only read at startup.
+    #[serde(default)]
+    pub history_path: Option<String>,
+    /// Secrets file with the bearer tokens that API clients must
+    /// authenticate with. If unset, the API is open to anyone who can reach
+    /// it. Only read at startup.
+    #[serde(default)]
+    pub tokens_path: Option<String>,
+    /// Serve the API over HTTPS. Only read at startup.
+    #[serde(default)]
+    pub tls: TlsConfig,
+}
+
+/// HTTPS configuration for the API
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// Serve HTTPS instead of plain HTTP
+    pub enabled: bool,
+    /// Path to the PEM certificate (chain) to serve
+    pub cert_path: String,
+    /// Path to the PEM private key for the certificate, in PKCS#8 or RSA
+    /// format
+    pub key_path: String,
+    /// If the certificate and key don't exist yet, generate a self-signed
+    /// pair at their paths. Clients won't trust it by default, so they'll
+    /// need to pin the certificate, or skip verification.
+    pub generate_self_signed: bool,
+    /// Host names and IP addresses that a generated certificate is valid for
+    pub self_signed_names: Vec<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert_path: "./config/tls/cert.pem".into(),
+            key_path: "./config/tls/key.pem".into(),
+            generate_self_signed: false,
+            self_signed_names: vec!["localhost".into()],
+        }
+    }
+}
+
+fn default_backup_dir() -> String {
+    "./config/backups".into()
+}
+
+fn default_max_backups() -> usize {
+    20
+}
+
+fn default_history_size() -> usize {
+    50
 }
 
 /// General configuration fields, that don't fall under any other category
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct GeneralConfig {
     /// Path to the I2C device on the system
     pub i2c_device_path: String,
 }
 
+/// The config fields that the drive motor controller is initialized from. If
+/// any of these change, the controller has to be reinitialized.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MotorHardwareConfig {
+    pub simulate: bool,
+    pub i2c_address: u8,
+    pub i2c_device_path: String,
+}
+
 /// The four different drive motors on the robot, defined by their position on
 /// the robot body
-#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[derive(
+    Copy, Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Hash,
+)]
 #[serde(rename_all = "snake_case")]
 pub enum DriveMotorLocation {
     FrontLeft,
@@ -97,13 +394,76 @@ impl DriveMotorLocation {
 }
 
 impl RobotConfig {
-    pub fn load(config_path: &str) -> anyhow::Result<Self> {
-        info!("Reading config from {}", config_path);
+    /// Get the fields that the drive motor controller depends on
+    pub fn motor_hardware(&self) -> MotorHardwareConfig {
+        MotorHardwareConfig {
+            simulate: self.drive.simulate,
+            i2c_address: self.drive.i2c_address,
+            i2c_device_path: self.general.i2c_device_path.clone(),
+        }
+    }
+
+    /// Load the config from all sources, layered on top of each other
+    pub fn load(sources: &ConfigSources) -> anyhow::Result<Self> {
+        info!("Reading config from {}", sources.path);
         let mut s = Config::new();
+        for (_, layer) in sources.layers()? {
+            s.merge(layer)?;
+        }
+        Ok(s.try_into()?)
+    }
 
-        s.merge(File::with_name(config_path))?;
-        // may want to add more config sources here at some point
+    /// Load the config, and get every value in it along with the source that
+    /// set it. Values that no source set come from defaults.
+    pub fn load_with_sources(
+        sources: &ConfigSources,
+    ) -> anyhow::Result<Vec<ConfigValue>> {
+        let config = Self::load(sources)?;
+        let key_sources = sources.key_sources()?;
 
-        Ok(s.try_into()?)
+        let mut values = Vec::new();
+        flatten("", serde_json::to_value(&config)?, &mut values);
+        Ok(values
+            .into_iter()
+            .map(|(key, value)| {
+                let source = key_sources
+                    .get(&key)
+                    .cloned()
+                    .unwrap_or_else(|| "default".into());
+                ConfigValue { key, value, source }
+            })
+            .collect())
+    }
+}
+
+/// Flatten nested tables into a list of `(dotted.key, value)`. Arrays are
+/// treated as single values.
+fn flatten(prefix: &str, value: Value, output: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&key, value, output);
+            }
+        }
+        value => output.push((prefix.into(), value)),
+    }
+}
+
+#[cfg(test)]
+impl RobotConfig {
+    /// Load the repo's default config, for tests that need a whole config
+    pub fn test_default() -> Self {
+        Self::load(&ConfigSources {
+            path: concat!(env!("CARGO_MANIFEST_DIR"), "/config/default.toml")
+                .into(),
+            overrides: Vec::new(),
+            sets: Vec::new(),
+        })
+        .unwrap()
     }
 }
@@ -0,0 +1,276 @@
+use crate::{
+    config::{ApiConfig, RobotConfig},
+    migrate,
+    persist::{self, Change},
+};
+use anyhow::Context;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::SystemTime,
+};
+
+/// One version of the config, as it was applied
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Revision {
+    /// Sequential ID, unique within the history
+    pub id: u64,
+    /// When the config was applied, in RFC 3339 format
+    pub timestamp: String,
+    /// The API client that made the change, if it came from the API. This is
+    /// its address, along with the name of its token if it used one.
+    pub client: Option<String>,
+    /// What made the change, e.g. `PATCH /config`
+    pub description: String,
+    pub config: RobotConfig,
+}
+
+/// A [Revision] as it's saved in the history file. Its config may be from an
+/// older version, so it's only parsed once it's been migrated.
+#[derive(Debug, Deserialize)]
+struct SavedRevision {
+    id: u64,
+    timestamp: String,
+    client: Option<String>,
+    description: String,
+    config: Value,
+}
+
+/// A [Revision] without its config, for listing
+#[derive(Clone, Debug, Serialize)]
+pub struct RevisionSummary {
+    pub id: u64,
+    pub timestamp: String,
+    pub client: Option<String>,
+    pub description: String,
+}
+
+/// One value that differs between two revisions, as reported via the API
+#[derive(Clone, Debug, Serialize)]
+pub struct RevisionChange {
+    /// Dotted path to the value, e.g. `input.speed.turbo_speed`
+    pub path: String,
+    /// The value in the older revision, or null if it wasn't set
+    pub old: Option<Value>,
+    /// The value in the newer revision, or null if it wasn't set
+    pub new: Option<Value>,
+}
+
+impl From<Change> for RevisionChange {
+    fn from(change: Change) -> Self {
+        Self {
+            path: change.key.join("."),
+            old: change.old,
+            new: change.new,
+        }
+    }
+}
+
+/// The most recent versions of the config, so changes can be audited and
+/// rolled back. Optionally saved to a JSON file, so it survives restarts.
+#[derive(Debug)]
+pub struct ConfigHistory {
+    /// Oldest first
+    revisions: VecDeque<Revision>,
+    max_revisions: usize,
+    /// Sends the serialized history to a background thread that saves it,
+    /// if it's saved to a file. The history is locked while handling
+    /// requests, so it shouldn't wait on the disk.
+    saver: Option<mpsc::Sender<String>>,
+}
+
+impl ConfigHistory {
+    /// Start a history with the settings from the API config. If it's saved
+    /// to a file, load the existing revisions from there.
+    pub fn load(config: &ApiConfig) -> Self {
+        let mut revisions = VecDeque::new();
+        let mut saver = None;
+        if let Some(path) = &config.history_path {
+            if Path::new(path).exists() {
+                info!("Reading config history from {}", path);
+                match load_revisions(path) {
+                    Ok(loaded) => revisions = loaded,
+                    Err(err) => warn!(
+                        "Error reading config history from {}, starting a \
+                        new one: {:#}",
+                        path, err
+                    ),
+                }
+            }
+            match spawn_saver(path.into()) {
+                Ok(sender) => saver = Some(sender),
+                Err(err) => error!(
+                    "Error starting config history saver, it won't be saved: \
+                    {:?}",
+                    err
+                ),
+            }
+        }
+
+        // The history size may have shrunk since it was saved
+        let mut history = Self {
+            revisions,
+            max_revisions: config.history_size,
+            saver,
+        };
+        history.trim();
+        history
+    }
+
+    /// Add a revision for a newly applied config. If it's the same as the
+    /// latest revision, nothing is added. The oldest revisions are dropped
+    /// once the history is full.
+    pub fn record(
+        &mut self,
+        config: &RobotConfig,
+        client: Option<&str>,
+        description: impl Into<String>,
+    ) {
+        let description = description.into();
+        if let Some(latest) = self.revisions.back() {
+            if same_config(&latest.config, config) {
+                debug!("Config unchanged by {}, not recording it", description);
+                return;
+            }
+        }
+
+        let id = self.revisions.back().map_or(1, |latest| latest.id + 1);
+        info!("Recording config revision {} ({})", id, description);
+        self.revisions.push_back(Revision {
+            id,
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now())
+                .to_string(),
+            client: client.map(String::from),
+            description,
+            config: config.clone(),
+        });
+        self.trim();
+
+        if let Err(err) = self.save() {
+            error!("Error saving config history: {:?}", err);
+        }
+    }
+
+    /// Drop the oldest revisions beyond the history size. The latest one is
+    /// always kept.
+    fn trim(&mut self) {
+        while self.revisions.len() > self.max_revisions.max(1) {
+            self.revisions.pop_front();
+        }
+    }
+
+    /// Queue the history to be written to its file, if it has one
+    fn save(&self) -> anyhow::Result<()> {
+        if let Some(saver) = &self.saver {
+            let content = serde_json::to_string_pretty(&self.revisions)?;
+            saver
+                .send(content)
+                .context("Config history saver has stopped")?;
+        }
+        Ok(())
+    }
+
+    /// List all revisions, oldest first
+    pub fn list(&self) -> Vec<RevisionSummary> {
+        self.revisions
+            .iter()
+            .map(|revision| RevisionSummary {
+                id: revision.id,
+                timestamp: revision.timestamp.clone(),
+                client: revision.client.clone(),
+                description: revision.description.clone(),
+            })
+            .collect()
+    }
+
+    /// Get a revision by ID, if it's still in the history
+    pub fn get(&self, id: u64) -> Option<&Revision> {
+        self.revisions.iter().find(|revision| revision.id == id)
+    }
+
+    /// Get the most recent revision
+    pub fn latest(&self) -> Option<&Revision> {
+        self.revisions.back()
+    }
+}
+
+/// Read the revisions from a history file, migrating each one's config to the
+/// current version. Revisions that can't be migrated are dropped.
+fn load_revisions(path: &str) -> anyhow::Result<VecDeque<Revision>> {
+    let content = fs::read_to_string(path)?;
+    let saved: Vec<SavedRevision> = serde_json::from_str(&content)?;
+    Ok(saved
+        .into_iter()
+        .filter_map(|revision| {
+            let name = format!("{} revision {}", path, revision.id);
+            let mut config = revision.config;
+            let config = migrate::migrate(&name, &mut config)
+                .and_then(|_| Ok(serde_json::from_value(config)?));
+            match config {
+                Ok(config) => Some(Revision {
+                    id: revision.id,
+                    timestamp: revision.timestamp,
+                    client: revision.client,
+                    description: revision.description,
+                    config,
+                }),
+                Err(err) => {
+                    warn!("Dropping {} from the history: {:#}", name, err);
+                    None
+                }
+            }
+        })
+        .collect())
+}
+
+/// Start a background thread that writes each serialized history it's sent
+/// to the file at `path`, in order. If several are waiting, only the latest
+/// is written.
+fn spawn_saver(path: PathBuf) -> anyhow::Result<mpsc::Sender<String>> {
+    let (tx, rx) = mpsc::channel::<String>();
+    thread::Builder::new()
+        .name("history-saver".into())
+        .spawn(move || {
+            while let Ok(mut content) = rx.recv() {
+                while let Ok(newer) = rx.try_recv() {
+                    content = newer;
+                }
+                if let Err(err) = persist::write_atomic(&path, &content)
+                    .with_context(|| {
+                        format!("Writing config history to {}", path.display())
+                    })
+                {
+                    error!("Error saving config history: {:?}", err);
+                }
+            }
+        })?;
+    Ok(tx)
+}
+
+/// Find every value that differs between two revisions
+pub fn diff_revisions(
+    old: &Revision,
+    new: &Revision,
+) -> anyhow::Result<Vec<RevisionChange>> {
+    let old = serde_json::to_value(&old.config)?;
+    let new = serde_json::to_value(&new.config)?;
+    Ok(persist::diff(&old, &new)
+        .into_iter()
+        .map(RevisionChange::from)
+        .collect())
+}
+
+/// Are two configs the same? Compared as JSON, since the config types don't
+/// implement [PartialEq].
+fn same_config(a: &RobotConfig, b: &RobotConfig) -> bool {
+    match (serde_json::to_value(a), serde_json::to_value(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
@@ -1,26 +1,109 @@
 use crate::{
-    config::{DriveInputMapping, DriveMotorLocation},
-    motors::MotorChannel,
+    calibration::CalibrationStore,
+    config::{DriveMotorLocation, InputConfig},
+    schema::AxisName,
+    validation::ConfigProblems,
 };
-use gilrs::{Axis, Gamepad, GamepadId, Gilrs};
-use log::{debug, error, info, trace, warn};
+use anyhow::Context;
+use gilrs::{Axis, Button};
+use log::{error, info, trace, warn};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+mod control;
+mod filter;
+mod gamepad;
+mod keyboard;
+mod mapping;
+mod network;
+mod recording;
+
+use control::ControlState;
+pub use control::{ControlConfig, ControlSlot, ControlStatus};
+pub use filter::AxisFilter;
+use filter::FilterState;
+use gamepad::GamepadSource;
+pub use keyboard::KeyboardConfig;
+use keyboard::KeyboardSource;
+pub use mapping::{ManualMapping, RacingMapping, TankMapping};
+use network::NetworkSource;
+pub use network::{JoystickMessage, NetworkConfig, NetworkJoysticks};
+use recording::{DeviceFrame, InputRecorder, ReplaySource};
+
+/// Button values above this count as pressed
+const BUTTON_THRESHOLD: f32 = 0.5;
+
+/// Every axis that gilrs knows about, besides `Unknown`. TODO use strum
+pub const AXES: &[Axis] = &[
+    Axis::LeftStickX,
+    Axis::LeftStickY,
+    Axis::LeftZ,
+    Axis::RightStickX,
+    Axis::RightStickY,
+    Axis::RightZ,
+    Axis::DPadX,
+    Axis::DPadY,
+];
+
+/// Every button that gilrs knows about, besides `Unknown`. TODO use strum
+pub const BUTTONS: &[Button] = &[
+    Button::South,
+    Button::East,
+    Button::North,
+    Button::West,
+    Button::C,
+    Button::Z,
+    Button::LeftTrigger,
+    Button::LeftTrigger2,
+    Button::RightTrigger,
+    Button::RightTrigger2,
+    Button::Select,
+    Button::Start,
+    Button::Mode,
+    Button::LeftThumb,
+    Button::RightThumb,
+    Button::DPadUp,
+    Button::DPadDown,
+    Button::DPadLeft,
+    Button::DPadRight,
+];
 
 /// An input mapping defines how inputs on a gamepad are mapped to values on the
-/// robot.
+/// robot. Each drive mapping type implements this, and is registered by name
+/// in [DriveInputMapping](crate::config::DriveInputMapping).
 pub trait InputMapping: Debug {
     /// Determine the necessary input value for a motor, based on the current
-    /// input state. Return None if the value cannot be read from input.
+    /// input state of the device in the given gamepad slot. `gamepad` is None
+    /// if no slot is currently in control. Return None if the value cannot be
+    /// read from input.
     fn motor_value(
         &self,
         handler: &InputHandler,
-        motor: MotorChannel,
+        gamepad: Option<&str>,
+        motor: DriveMotorLocation,
     ) -> Option<f32>;
+
+    /// Check this mapping's config for problems that deserialization doesn't
+    /// catch. `path` is where the mapping is in the config.
+    fn validate(&self, _path: &str, _problems: &mut ConfigProblems) {}
+
+    /// Should this mapping's motor values be scaled by the speed gears? Gears
+    /// are for driving with a gamepad, so mappings that set motor speeds
+    /// directly shouldn't be.
+    fn uses_speed_gears(&self) -> bool {
+        true
+    }
 }
 
 /// A formula used to transform input axis values into output axis values.
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum AxisTransformation {
     /// Simple transformation that makes no changes (x => x)
@@ -46,119 +129,587 @@ impl Default for AxisTransformation {
 }
 
 /// An analog axis on a gamepad.
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct InputAxis {
     /// The axis on the gamepad that we read
+    #[schemars(with = "AxisName")]
     pub axis: Axis,
     /// A transformation to be applied to any value read from this axis
     pub transformation: AxisTransformation,
+    /// An optional filter to smooth out noise, applied before the
+    /// transformation
+    #[serde(default)]
+    pub filter: Option<AxisFilter>,
+}
+
+/// Where a trigger's value is read from. The JSON Schema for this is written
+/// by hand, see [crate::schema].
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerSource {
+    /// An analog axis, e.g. `LeftZ` or `RightZ`. It's read as resting at 0,
+    /// unless the trigger is `bipolar`.
+    Axis(Axis),
+    /// An analog button, e.g. `LeftTrigger2` or `RightTrigger2`. Its value is
+    /// already in [0, 1].
+    Button(Button),
+}
+
+/// A unipolar input, such as an analog trigger. Unlike an [InputAxis], its
+/// value is always in [0, 1], where 0 is released.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct InputTrigger {
+    /// The axis or button on the gamepad that we read
+    #[serde(flatten)]
+    pub source: TriggerSource,
+    /// A transformation to be applied to any value read from this trigger
+    #[serde(default)]
+    pub transformation: AxisTransformation,
+    /// Set for axes that rest at -1 and are fully pressed at 1, so their
+    /// value is mapped onto [0, 1]. Ignored for buttons.
+    #[serde(default)]
+    pub bipolar: bool,
+}
+
+/// Information about one connected input device
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInfo {
+    /// Human-readable device name
+    pub name: String,
+    /// Stable unique ID for this kind of device. For physical gamepads, this
+    /// is the SDL GUID.
+    pub uuid: String,
+}
+
+/// Raw values of every axis and button on one device at some moment, for
+/// noticing when the user touches anything
+#[derive(Clone, Debug)]
+pub struct InputSnapshot {
+    device: DeviceId,
+    /// Values in the same order as [AXES]. Axes that haven't reported yet
+    /// are 0.
+    axes: Vec<f32>,
+    /// Values in the same order as [BUTTONS]
+    buttons: Vec<f32>,
+}
+
+impl InputSnapshot {
+    /// The device that the snapshot was taken of
+    pub fn device(&self) -> DeviceId {
+        self.device
+    }
+
+    /// Has any axis moved, or any button been pressed, further than
+    /// `threshold` since the earlier snapshot? Releasing a button doesn't
+    /// count, and neither do the `ignored` buttons.
+    pub fn moved_since(
+        &self,
+        earlier: &InputSnapshot,
+        threshold: f32,
+        ignored: &HashSet<Button>,
+    ) -> bool {
+        let axis_moved = self
+            .axes
+            .iter()
+            .zip(&earlier.axes)
+            .any(|(value, earlier)| (value - earlier).abs() > threshold);
+        let button_pressed = BUTTONS
+            .iter()
+            .zip(self.buttons.iter().zip(&earlier.buttons))
+            .filter(|(button, _)| !ignored.contains(button))
+            .any(|(_, (value, earlier))| value - earlier > threshold);
+        axis_moved || button_pressed
+    }
+}
+
+/// Identifies one input device, across all input sources
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DeviceId {
+    /// Index of the source in the input handler
+    source: usize,
+    /// ID of the device within its source
+    device: usize,
+}
+
+/// A source of input devices, e.g. physical gamepads or the terminal
+/// keyboard. Each source provides zero or more devices, which can be bound to
+/// gamepad slots.
+pub trait InputSource: Debug {
+    /// Process any pending input. Called once per loop, before any reads.
+    fn update(&mut self);
+
+    /// Get all currently connected devices, with their source-specific IDs
+    fn devices(&self) -> Vec<(usize, DeviceInfo)>;
+
+    /// Read the current raw value of an axis on a device, in [-1, 1]. Returns
+    /// None if the device isn't connected or doesn't have the axis.
+    fn axis_value(&self, device: usize, axis: Axis) -> Option<f32>;
+
+    /// Read the current value of a button on a device, in [0, 1]. Digital
+    /// buttons are always 0 or 1. Returns None if the device isn't connected
+    /// or doesn't have the button.
+    fn button_value(&self, device: usize, button: Button) -> Option<f32>;
+
+    /// Has the user asked (via this source) for the robot to shut down?
+    fn quit_requested(&self) -> bool {
+        false
+    }
+
+    /// Time since the source started, if it keeps its own clock that input
+    /// should be timed against (e.g. a stepped replay). Otherwise, input is
+    /// timed against the real clock.
+    fn elapsed(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// A rule for picking a device out of all the connected ones. A gamepad slot
+/// in the config has a list of these, which are tried in order.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GamepadSelector {
+    /// Match any device whose name contains this string (case-insensitive)
+    Name(String),
+    /// Match the device with this UUID, e.g. 32 hex digits for a gamepad
+    /// (dashes optional)
+    Uuid(String),
+    /// Match the nth connected device (0-indexed). Physical gamepads come
+    /// first, in the order gilrs lists them.
+    Index(usize),
+}
+
+impl GamepadSelector {
+    /// Does this selector match the given device? `index` is the device's
+    /// position in the list of connected devices.
+    fn matches(&self, index: usize, device: &DeviceInfo) -> bool {
+        match self {
+            Self::Name(pattern) => {
+                device.name.to_lowercase().contains(&pattern.to_lowercase())
+            }
+            Self::Uuid(uuid) => {
+                uuid.replace('-', "").to_lowercase() == device.uuid
+            }
+            Self::Index(i) => *i == index,
+        }
+    }
+}
+
+/// The connection state of a gamepad slot
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ConnectionState {
+    /// No matching device is connected, so no input can be read
+    #[default]
+    Disconnected,
+    /// A matching device is connected and in use
+    Connected {
+        id: DeviceId,
+        name: String,
+        uuid: String,
+    },
+}
+
+/// Get all physical gamepads that are currently connected, in the order
+/// that index selectors count them
+pub fn connected_gamepads(
+    config: &InputConfig,
+) -> anyhow::Result<Vec<(usize, DeviceInfo)>> {
+    Ok(GamepadSource::new(config)?.devices())
 }
 
 #[derive(Debug)]
 pub struct InputHandler {
-    gil: Gilrs,
-    // Active gamepad in use. None if there is none connected.
-    gamepad_id: Option<GamepadId>,
+    /// All sources that we read input devices from. Physical gamepads are
+    /// always first.
+    sources: Vec<Box<dyn InputSource>>,
+    /// The device bound to each gamepad slot in the input config. Slots with
+    /// no matching device connected have no entry.
+    gamepads: HashMap<String, (DeviceId, DeviceInfo)>,
+    /// Axis calibrations, applied to every raw axis value. This is shared
+    /// with the API, and is only ever locked briefly, so a sync lock is fine.
+    calibration: Arc<RwLock<CalibrationStore>>,
+    /// If enabled, records all raw input to a file
+    recorder: Option<InputRecorder>,
+    /// State of each filtered axis, keyed by gamepad slot and axis. This
+    /// needs to be updated on reads, hence the lock.
+    filters: Mutex<HashMap<(String, Axis), FilterState>>,
+    /// Which slot is in control of the drive
+    control: ControlState,
+    /// When the handler was created
+    start: Instant,
+    /// The time as of the last update, which filters are timed against.
+    /// Normally this is just the clock, but a stepped replay has its own.
+    now: Instant,
 }
 
 impl InputHandler {
-    pub fn new() -> Self {
-        let gil = Gilrs::new().unwrap();
+    pub fn new(
+        config: &InputConfig,
+        calibration: Arc<RwLock<CalibrationStore>>,
+        network_joysticks: Option<NetworkJoysticks>,
+    ) -> anyhow::Result<Self> {
+        let mut sources: Vec<Box<dyn InputSource>> = Vec::new();
+        if let Some(replay_path) = &config.replay_path {
+            // A replay stands in for all real input devices
+            sources.push(Box::new(ReplaySource::load(
+                replay_path,
+                config.replay_step_ms.map(Duration::from_millis),
+            )?));
+        } else {
+            sources.push(Box::new(GamepadSource::new(config)?));
+            if config.keyboard.enabled {
+                sources.push(Box::new(
+                    KeyboardSource::new(&config.keyboard)
+                        .context("Initializing keyboard input")?,
+                ));
+            }
+            if let Some(network_joysticks) = network_joysticks {
+                sources.push(Box::new(NetworkSource::new(
+                    &config.network,
+                    network_joysticks,
+                )));
+            }
+        }
+
+        let recorder = match &config.record_path {
+            None => None,
+            Some(record_path) => Some(InputRecorder::new(record_path)?),
+        };
+
         let mut rv = Self {
-            gil,
-            gamepad_id: None,
+            sources,
+            gamepads: HashMap::new(),
+            calibration,
+            recorder,
+            filters: Mutex::new(HashMap::new()),
+            control: ControlState::default(),
+            start: Instant::now(),
+            now: Instant::now(),
         };
 
-        // Try to set up the gamepad. If none is present, just log an error and
-        // move on.
-        rv.init_gamepad();
-        if rv.gamepad_id.is_none() {
+        // Try to set up the gamepads. If none is present, just log an error
+        // and move on.
+        rv.bind_gamepads(config);
+        if rv.gamepads.is_empty() {
             error!("No gamepad found, initializing without one")
         }
+        rv.update_control(config);
+
+        Ok(rv)
+    }
+
+    /// Process all pending input, then (re)bind each gamepad slot to the best
+    /// matching connected device. This keeps each source's cached state up to
+    /// date, and handles devices being connected/disconnected. If a bound
+    /// device disconnects, its slot falls back to the next matching device,
+    /// or no input at all. This should be called once per loop, before
+    /// reading any input.
+    pub fn update(&mut self, config: &InputConfig) {
+        for source in &mut self.sources {
+            source.update();
+        }
+        self.now = match self.sources.iter().find_map(|source| source.elapsed())
+        {
+            Some(elapsed) => self.start + elapsed,
+            None => Instant::now(),
+        };
+
+        if self.recorder.is_some() {
+            let devices = self
+                .devices()
+                .into_iter()
+                .map(|(id, info)| {
+                    DeviceFrame::capture(
+                        id,
+                        info,
+                        self.sources[id.source].as_ref(),
+                    )
+                })
+                .collect();
+            if let Some(recorder) = &mut self.recorder {
+                recorder.record(devices);
+            }
+        }
+
+        self.bind_gamepads(config);
+        self.update_control(config);
+
+        // If a calibration is running, feed it the latest values
+        let mut calibration = self.calibration.write().unwrap();
+        for (id, info) in self.gamepads.values() {
+            let source = &self.sources[id.source];
+            calibration
+                .record(&info.uuid, |axis| source.axis_value(id.device, axis));
+        }
+    }
 
-        rv
+    /// Has the user asked for the robot to shut down, via any input source?
+    pub fn quit_requested(&self) -> bool {
+        self.sources.iter().any(|source| source.quit_requested())
     }
 
-    /// Initialize the input gamepad for this handler. If the gamepad is already
-    /// initialized, this does nothing. This can safely be called on every
-    /// loop, to enable hot-plugging.
-    pub fn init_gamepad(&mut self) {
-        // If we have no input set up, attempt to connect a new one
-        if self.gamepad_id.is_none() {
-            // Grab the first gamepad
-            match self.gil.gamepads().next() {
+    /// Get the connection state of each gamepad slot in the input config
+    pub fn connection_states(
+        &self,
+        config: &InputConfig,
+    ) -> HashMap<String, ConnectionState> {
+        config
+            .gamepads
+            .keys()
+            .map(|slot| {
+                let state = match self.gamepads.get(slot) {
+                    None => ConnectionState::Disconnected,
+                    Some((id, info)) => ConnectionState::Connected {
+                        id: *id,
+                        name: info.name.clone(),
+                        uuid: info.uuid.clone(),
+                    },
+                };
+                (slot.clone(), state)
+            })
+            .collect()
+    }
+
+    /// Get the slot that's in control of the drive, if any
+    pub fn controlling_slot(&self) -> Option<&str> {
+        self.control.controller()
+    }
+
+    /// Get which slot is in control of the drive, for the API
+    pub fn control_status(&self) -> ControlStatus {
+        let slot = self.control.controller();
+        ControlStatus {
+            slot: slot.map(String::from),
+            device: slot
+                .and_then(|slot| self.gamepads.get(slot))
+                .map(|(_, info)| info.name.clone()),
+            students_disabled: self.control.students_disabled(),
+        }
+    }
+
+    /// Check the control buttons on each slot that's allowed to drive, and
+    /// hand control of the drive to the right slot
+    fn update_control(&mut self, config: &InputConfig) {
+        let slots: Cow<[ControlSlot]> = if config.control.slots.is_empty() {
+            Cow::Owned(vec![ControlSlot {
+                slot: config.drive_gamepad.clone(),
+                priority: 0,
+                instructor: false,
+            }])
+        } else {
+            Cow::Borrowed(&config.control.slots)
+        };
+        let buttons = [
+            config.control.take_control_button,
+            config.control.disable_students_button,
+        ];
+        let held_buttons = slots
+            .iter()
+            .filter(|slot| self.gamepads.contains_key(&slot.slot))
+            .map(|slot| {
+                let held: HashSet<Button> = buttons
+                    .iter()
+                    .copied()
+                    .flatten()
+                    .filter(|&button| {
+                        self.is_button_pressed(&slot.slot, button)
+                    })
+                    .collect();
+                (slot.slot.clone(), held)
+            })
+            .collect();
+
+        if self.control.update(&config.control, &slots, held_buttons) {
+            match self.control.controller() {
+                None => warn!("No gamepad slot has drive control"),
+                Some(slot) => info!(
+                    "Slot {:?} ({}) has drive control",
+                    slot,
+                    self.gamepads
+                        .get(slot)
+                        .map_or("unknown device", |(_, info)| &info.name)
+                ),
+            }
+        }
+    }
+
+    /// Get all connected devices, across all sources
+    fn devices(&self) -> Vec<(DeviceId, DeviceInfo)> {
+        self.sources
+            .iter()
+            .enumerate()
+            .flat_map(|(source_index, source)| {
+                source.devices().into_iter().map(move |(device, info)| {
+                    (
+                        DeviceId {
+                            source: source_index,
+                            device,
+                        },
+                        info,
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Bind each gamepad slot to the first connected device that matches one
+    /// of its selectors. Selectors are tried in order, so if a more preferred
+    /// device is connected later on, the slot will switch over to it.
+    fn bind_gamepads(&mut self, config: &InputConfig) {
+        // Forget about any slots that were removed from the config
+        self.gamepads
+            .retain(|slot, _| config.gamepads.contains_key(slot));
+
+        let devices = self.devices();
+        for (slot, selectors) in &config.gamepads {
+            let old = self.gamepads.get(slot);
+            let new = find_device(&devices, selectors);
+            if old == new {
+                continue;
+            }
+
+            match new {
                 None => {
-                    trace!("No gamepad connected");
+                    self.gamepads.remove(slot);
+                    error!("No gamepad available for slot {:?}", slot);
                 }
-                Some((gamepad_id, gamepad)) => {
+                Some((id, info)) => {
                     info!(
-                        "Using gamepad {} (id={})",
-                        gamepad.name(),
-                        gamepad_id
-                    );
-                    debug!(
-                        "Gamepad mapping source: {:?}",
-                        gamepad.mapping_source()
+                        "Using gamepad {} ({:?}) for slot {:?}",
+                        info.name, id, slot
                     );
-                    self.gamepad_id = Some(gamepad_id);
+                    self.gamepads.insert(slot.clone(), (*id, info.clone()));
                 }
             }
         }
     }
 
-    fn gamepad(&self) -> Option<Gamepad<'_>> {
-        self.gamepad_id.map(|id| self.gil.gamepad(id))
+    /// Read an input value from the given axis on the device in the given
+    /// slot, then apply the device's calibration (if any), the axis's filter
+    /// (if any) and the axis's transformation. If the device is not connected
+    /// or the axis is not known, return None.
+    pub fn read_axis(&self, slot: &str, axis: InputAxis) -> Option<f32> {
+        let (id, info) = self.gamepads.get(slot)?;
+        let raw_value =
+            match self.sources[id.source].axis_value(id.device, axis.axis) {
+                None => {
+                    warn!("Could not read axis {:?} ", axis.axis);
+                    None
+                }
+                Some(value) => Some(value),
+            }?;
+        let value =
+            match self.calibration.read().unwrap().get(&info.uuid, axis.axis) {
+                None => raw_value,
+                Some(axis_calibration) => axis_calibration.apply(raw_value),
+            };
+        let value = match axis.filter {
+            None => value,
+            Some(filter) => {
+                let now = self.now;
+                self.filters
+                    .lock()
+                    .unwrap()
+                    .entry((slot.to_owned(), axis.axis))
+                    .or_insert_with(|| FilterState::new(value, now))
+                    .apply(filter, value, now)
+            }
+        };
+        Some(axis.transformation.transform(value))
     }
 
-    /// Read an input value from the given axis, and apply the axis's
-    /// transformation. If the gamepad is not connected or the axis is not
-    /// known, return None.
-    pub fn read_axis(&self, axis: InputAxis) -> Option<f32> {
-        let gamepad = self.gamepad()?;
-        let raw_value = match gamepad.axis_data(axis.axis) {
-            None => {
-                warn!("Could not read axis {:?} ", axis.axis);
-                None
+    /// Read the value of a trigger on the device in the given slot, in [0, 1],
+    /// then apply the trigger's transformation. Triggers that haven't been
+    /// touched yet may not report a value, so they're treated as released. If
+    /// the device is not connected, return None.
+    pub fn read_trigger(
+        &self,
+        slot: &str,
+        trigger: InputTrigger,
+    ) -> Option<f32> {
+        let (id, _) = self.gamepads.get(slot)?;
+        let source = &self.sources[id.source];
+        let value = match trigger.source {
+            TriggerSource::Axis(axis) => {
+                match source.axis_value(id.device, axis) {
+                    // Map [-1, 1] onto [0, 1]
+                    Some(value) if trigger.bipolar => (value + 1.0) / 2.0,
+                    Some(value) => value,
+                    None => 0.0,
+                }
             }
-            Some(axis_data) => Some(axis_data.value()),
-        }?;
-        Some(axis.transformation.transform(raw_value))
+            TriggerSource::Button(button) => {
+                source.button_value(id.device, button).unwrap_or(0.0)
+            }
+        };
+        Some(trigger.transformation.transform(value.clamp(0.0, 1.0)))
+    }
+
+    /// Read the current value of a button on the device in the given slot, in
+    /// [0, 1]. If the device is not connected or doesn't have the button,
+    /// return None.
+    pub fn read_button(&self, slot: &str, button: Button) -> Option<f32> {
+        let (id, _) = self.gamepads.get(slot)?;
+        self.sources[id.source].button_value(id.device, button)
+    }
+
+    /// Is the given button currently held on the device in the given slot?
+    pub fn is_button_pressed(&self, slot: &str, button: Button) -> bool {
+        self.read_button(slot, button)
+            .is_some_and(|value| value > BUTTON_THRESHOLD)
+    }
+
+    /// Take a snapshot of every raw axis and button on the device in the
+    /// given slot. Raw values are used, so this covers inputs that aren't
+    /// used by any mapping. If the device is not connected, return None.
+    pub fn snapshot(&self, slot: &str) -> Option<InputSnapshot> {
+        let (id, _) = self.gamepads.get(slot)?;
+        let source = &self.sources[id.source];
+        Some(InputSnapshot {
+            device: *id,
+            axes: AXES
+                .iter()
+                .map(|&axis| source.axis_value(id.device, axis).unwrap_or(0.0))
+                .collect(),
+            buttons: BUTTONS
+                .iter()
+                .map(|&button| {
+                    source.button_value(id.device, button).unwrap_or(0.0)
+                })
+                .collect(),
+        })
     }
 
     /// Get the value for a specific motor. The corresponding input value will
-    /// be looked up using the input mapping. Returns `None` if we have no
-    /// gamepad connected.
+    /// be looked up using the drive input mapping, from the slot that's in
+    /// control. Returns `None` if no slot is in control.
     pub fn motor_value(
         &self,
-        drive_input_mapping: DriveInputMapping,
+        config: &InputConfig,
         motor: DriveMotorLocation,
     ) -> Option<f32> {
-        // Map the desired motor to a motor value, based on the input config
-        match drive_input_mapping {
-            DriveInputMapping::Tank {
-                left_motor_axis,
-                right_motor_axis,
-            } => {
-                // Map to an input axis, then read that
-                let axis = match motor {
-                    DriveMotorLocation::FrontLeft => left_motor_axis,
-                    DriveMotorLocation::FrontRight => right_motor_axis,
-                    DriveMotorLocation::BackLeft => left_motor_axis,
-                    DriveMotorLocation::BackRight => right_motor_axis,
-                };
-                self.read_axis(axis)
-            }
-            DriveInputMapping::Manual {
-                front_left,
-                front_right,
-                back_left,
-                back_right,
-            } => Some(match motor {
-                DriveMotorLocation::FrontLeft => front_left,
-                DriveMotorLocation::FrontRight => front_right,
-                DriveMotorLocation::BackLeft => back_left,
-                DriveMotorLocation::BackRight => back_right,
-            }),
-        }
+        config
+            .drive
+            .mapping()
+            .motor_value(self, self.controlling_slot(), motor)
+    }
+}
+
+/// Find the first connected device that matches any of the given selectors,
+/// trying each selector in order
+fn find_device<'a>(
+    devices: &'a [(DeviceId, DeviceInfo)],
+    selectors: &[GamepadSelector],
+) -> Option<&'a (DeviceId, DeviceInfo)> {
+    let found = selectors.iter().find_map(|selector| {
+        devices
+            .iter()
+            .enumerate()
+            .find(|(i, (_, info))| selector.matches(*i, info))
+            .map(|(_, device)| device)
+    });
+    if found.is_none() {
+        trace!("No device matching {:?}", selectors);
     }
+    found
 }
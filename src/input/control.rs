@@ -0,0 +1,320 @@
+use crate::schema::ButtonName;
+use gilrs::Button;
+use log::info;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Configuration for sharing control of the drive system between multiple
+/// gamepad slots, e.g. a student and an instructor. Only one slot controls the
+/// drive at a time.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ControlConfig {
+    /// Slots that are allowed to drive. If empty, only
+    /// [InputConfig::drive_gamepad](crate::config::InputConfig::drive_gamepad)
+    /// can drive.
+    pub slots: Vec<ControlSlot>,
+    /// Pressing this button takes control of the drive, if allowed
+    #[schemars(with = "Option<ButtonName>")]
+    pub take_control_button: Option<Button>,
+    /// Pressing this button on an instructor gamepad toggles whether
+    /// non-instructor gamepads are allowed to drive at all
+    #[schemars(with = "Option<ButtonName>")]
+    pub disable_students_button: Option<Button>,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            slots: Vec::new(),
+            take_control_button: Some(Button::Mode),
+            disable_students_button: Some(Button::Select),
+        }
+    }
+}
+
+/// A gamepad slot that is allowed to drive
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ControlSlot {
+    /// Name of the gamepad slot
+    pub slot: String,
+    /// When nobody has control, the connected slot with the highest priority
+    /// gets it. A slot can only take control from slots with the same or
+    /// lower priority.
+    #[serde(default)]
+    pub priority: i32,
+    /// An instructor can always take control, can't have control taken away
+    /// by a non-instructor, and can disable all non-instructor slots
+    #[serde(default)]
+    pub instructor: bool,
+}
+
+/// Which slot is in control of the drive, as reported via the API
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ControlStatus {
+    /// The gamepad slot in control, if any
+    pub slot: Option<String>,
+    /// Name of the device bound to that slot
+    pub device: Option<String>,
+    /// Are non-instructor slots currently blocked from driving?
+    pub students_disabled: bool,
+}
+
+/// Tracks which gamepad slot is in control of the drive
+#[derive(Debug, Default)]
+pub struct ControlState {
+    controller: Option<String>,
+    students_disabled: bool,
+    /// Buttons held on each slot during the last update, so each press is
+    /// only handled once
+    held_buttons: HashMap<String, HashSet<Button>>,
+}
+
+impl ControlState {
+    /// The gamepad slot currently in control, if any
+    pub fn controller(&self) -> Option<&str> {
+        self.controller.as_deref()
+    }
+
+    /// Are non-instructor slots currently blocked from driving?
+    pub fn students_disabled(&self) -> bool {
+        self.students_disabled
+    }
+
+    /// Handle button presses, and pick which slot is in control. `slots` is
+    /// the list of slots allowed to drive, and `held_buttons` has an entry
+    /// for each slot with a connected device, containing the control buttons
+    /// currently held on it. Returns true if the controlling slot changed.
+    pub fn update(
+        &mut self,
+        config: &ControlConfig,
+        slots: &[ControlSlot],
+        held_buttons: HashMap<String, HashSet<Button>>,
+    ) -> bool {
+        let is_pressed = |slot: &ControlSlot, button: Option<Button>| {
+            button.is_some_and(|button| {
+                let held = |buttons: &HashMap<String, HashSet<Button>>| {
+                    buttons
+                        .get(&slot.slot)
+                        .is_some_and(|buttons| buttons.contains(&button))
+                };
+                held(&held_buttons) && !held(&self.held_buttons)
+            })
+        };
+
+        let mut students_disabled = self.students_disabled;
+        for slot in slots {
+            if slot.instructor
+                && is_pressed(slot, config.disable_students_button)
+            {
+                students_disabled = !students_disabled;
+                info!(
+                    "Instructor {:?} {} student gamepads",
+                    slot.slot,
+                    if students_disabled {
+                        "disabled"
+                    } else {
+                        "enabled"
+                    }
+                );
+            }
+        }
+        let is_eligible = |slot: &ControlSlot| {
+            held_buttons.contains_key(&slot.slot)
+                && (slot.instructor || !students_disabled)
+        };
+
+        // Keep the current controller, as long as it's still allowed to drive
+        let mut controller = self.controller.as_ref().and_then(|controller| {
+            slots
+                .iter()
+                .find(|slot| &slot.slot == controller)
+                .filter(|slot| is_eligible(slot))
+        });
+
+        for slot in slots {
+            if is_eligible(slot) && is_pressed(slot, config.take_control_button)
+            {
+                let allowed = match controller {
+                    None => true,
+                    Some(current) => {
+                        slot.instructor
+                            || (!current.instructor
+                                && slot.priority >= current.priority)
+                    }
+                };
+                if allowed {
+                    controller = Some(slot);
+                } else {
+                    info!(
+                        "Slot {:?} can't take control from {:?}",
+                        slot.slot,
+                        controller.map(|current| &current.slot)
+                    );
+                }
+            }
+        }
+
+        // If nobody has control, hand it to the highest priority slot. On a
+        // tie, the first slot in the list wins.
+        if controller.is_none() {
+            controller = slots
+                .iter()
+                .rev()
+                .filter(|slot| is_eligible(slot))
+                .max_by_key(|slot| (slot.instructor, slot.priority));
+        }
+
+        let controller = controller.map(|slot| slot.slot.clone());
+        let changed = controller != self.controller;
+        self.controller = controller;
+        self.students_disabled = students_disabled;
+        self.held_buttons = held_buttons;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot(name: &str, priority: i32, instructor: bool) -> ControlSlot {
+        ControlSlot {
+            slot: name.into(),
+            priority,
+            instructor,
+        }
+    }
+
+    /// Build the held buttons for [ControlState::update]. Every slot listed
+    /// is connected.
+    fn held(slots: &[(&str, &[Button])]) -> HashMap<String, HashSet<Button>> {
+        slots
+            .iter()
+            .map(|(slot, buttons)| {
+                (slot.to_string(), buttons.iter().copied().collect())
+            })
+            .collect()
+    }
+
+    fn slots() -> Vec<ControlSlot> {
+        vec![
+            slot("student1", 0, false),
+            slot("student2", 0, false),
+            slot("helper", 1, false),
+            slot("instructor", 0, true),
+        ]
+    }
+
+    /// With nobody in control, instructors win, then the highest priority,
+    /// then the first in the list
+    #[test]
+    fn test_default_controller() {
+        let config = ControlConfig::default();
+        let mut state = ControlState::default();
+        assert!(!state.update(&config, &slots(), held(&[])));
+        assert_eq!(state.controller(), None);
+
+        state.update(
+            &config,
+            &slots(),
+            held(&[("student1", &[]), ("student2", &[])]),
+        );
+        assert_eq!(state.controller(), Some("student1"));
+
+        let mut state = ControlState::default();
+        state.update(
+            &config,
+            &slots(),
+            held(&[("student1", &[]), ("helper", &[])]),
+        );
+        assert_eq!(state.controller(), Some("helper"));
+
+        let mut state = ControlState::default();
+        state.update(
+            &config,
+            &slots(),
+            held(&[("helper", &[]), ("instructor", &[])]),
+        );
+        assert_eq!(state.controller(), Some("instructor"));
+    }
+
+    #[test]
+    fn test_take_control() {
+        let config = ControlConfig::default();
+        let mut state = ControlState::default();
+        let connected = [("student1", &[][..]), ("student2", &[])];
+        state.update(&config, &slots(), held(&connected));
+
+        // Same priority can take control, once per press
+        let pressed = [("student1", &[][..]), ("student2", &[Button::Mode])];
+        assert!(state.update(&config, &slots(), held(&pressed)));
+        assert_eq!(state.controller(), Some("student2"));
+        state.update(&config, &slots(), held(&connected));
+
+        // Higher priority takes control, and lower can't take it back
+        let connected = [
+            ("student1", &[][..]),
+            ("student2", &[]),
+            ("helper", &[Button::Mode]),
+        ];
+        assert!(state.update(&config, &slots(), held(&connected)));
+        assert_eq!(state.controller(), Some("helper"));
+        let pressed = [
+            ("student1", &[Button::Mode][..]),
+            ("student2", &[]),
+            ("helper", &[Button::Mode]),
+        ];
+        assert!(!state.update(&config, &slots(), held(&pressed)));
+        assert_eq!(state.controller(), Some("helper"));
+    }
+
+    #[test]
+    fn test_instructor() {
+        let config = ControlConfig::default();
+        let mut state = ControlState::default();
+        state.update(&config, &slots(), held(&[("helper", &[])]));
+        assert_eq!(state.controller(), Some("helper"));
+
+        // The instructor can take control from anyone, and keeps it
+        let connected = [("helper", &[][..]), ("instructor", &[Button::Mode])];
+        state.update(&config, &slots(), held(&connected));
+        assert_eq!(state.controller(), Some("instructor"));
+        let pressed = [("helper", &[Button::Mode][..]), ("instructor", &[])];
+        state.update(&config, &slots(), held(&pressed));
+        assert_eq!(state.controller(), Some("instructor"));
+    }
+
+    /// Disabling students takes control away from them, until they're enabled
+    /// again
+    #[test]
+    fn test_disable_students() {
+        let config = ControlConfig::default();
+        let mut state = ControlState::default();
+        let connected = [("student1", &[][..]), ("instructor", &[])];
+        let toggle = [("student1", &[][..]), ("instructor", &[Button::Select])];
+        state.update(&config, &slots(), held(&[("student1", &[])]));
+        assert_eq!(state.controller(), Some("student1"));
+
+        state.update(&config, &slots(), held(&toggle));
+        assert!(state.students_disabled());
+        assert_eq!(state.controller(), Some("instructor"));
+
+        // Students can't take control while disabled
+        let pressed = [("student1", &[Button::Mode][..]), ("instructor", &[])];
+        state.update(&config, &slots(), held(&pressed));
+        assert_eq!(state.controller(), Some("instructor"));
+
+        // Only the instructor can toggle it
+        let pressed =
+            [("student1", &[Button::Select][..]), ("instructor", &[])];
+        state.update(&config, &slots(), held(&pressed));
+        assert!(state.students_disabled());
+
+        state.update(&config, &slots(), held(&toggle));
+        assert!(!state.students_disabled());
+        state.update(&config, &slots(), held(&connected));
+        assert_eq!(state.controller(), Some("instructor"));
+    }
+}
@@ -0,0 +1,126 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// A smoothing filter for a noisy axis. Values are run through an exponential
+/// moving average, then small changes are ignored, so the output only moves
+/// once the input has really moved.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct AxisFilter {
+    /// Time constant of the moving average. After this long, the output will
+    /// have covered ~63% of a sudden change in input. 0 disables smoothing.
+    /// This is based on elapsed time, so it doesn't depend on the loop rate.
+    pub time_constant_ms: f32,
+    /// Changes in the smoothed value smaller than this are ignored. Values
+    /// within this distance of 0 are snapped to 0, so the axis always comes
+    /// back to rest.
+    pub hysteresis: f32,
+}
+
+impl Default for AxisFilter {
+    fn default() -> Self {
+        Self {
+            time_constant_ms: 50.0,
+            hysteresis: 0.02,
+        }
+    }
+}
+
+/// State of the filter for one axis, kept across loop iterations
+#[derive(Copy, Clone, Debug)]
+pub struct FilterState {
+    /// Output of the moving average
+    smoothed: f32,
+    /// Output after hysteresis, i.e. the value that's actually used
+    output: f32,
+    last_update: Instant,
+}
+
+impl FilterState {
+    /// Start a new filter, settled at the given value
+    pub fn new(value: f32, now: Instant) -> Self {
+        Self {
+            smoothed: value,
+            output: value,
+            last_update: now,
+        }
+    }
+
+    /// Feed a new raw value into the filter, and get the filtered value
+    pub fn apply(
+        &mut self,
+        filter: AxisFilter,
+        value: f32,
+        now: Instant,
+    ) -> f32 {
+        let elapsed_ms = now
+            .saturating_duration_since(self.last_update)
+            .as_secs_f32()
+            * 1000.0;
+        self.last_update = now;
+
+        // Weight the new value by how much time has passed, so reading the
+        // axis more or less often doesn't change the response
+        let alpha = if filter.time_constant_ms > 0.0 {
+            1.0 - (-elapsed_ms / filter.time_constant_ms).exp()
+        } else {
+            1.0
+        };
+        self.smoothed += alpha * (value - self.smoothed);
+
+        if self.smoothed.abs() < filter.hysteresis {
+            self.output = 0.0;
+        } else if (self.smoothed - self.output).abs() >= filter.hysteresis {
+            self.output = self.smoothed;
+        }
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Hysteresis only, so each value goes straight through the average
+    const HYSTERESIS: AxisFilter = AxisFilter {
+        time_constant_ms: 0.0,
+        hysteresis: 0.1,
+    };
+
+    #[test]
+    fn test_hysteresis() {
+        let now = Instant::now();
+        let mut state = FilterState::new(0.5, now);
+        // Small changes are ignored...
+        assert_eq!(state.apply(HYSTERESIS, 0.55, now), 0.5);
+        assert_eq!(state.apply(HYSTERESIS, 0.45, now), 0.5);
+        // ...until the input really moves
+        assert_eq!(state.apply(HYSTERESIS, 0.7, now), 0.7);
+        assert_eq!(state.apply(HYSTERESIS, 0.65, now), 0.7);
+    }
+
+    /// Values near 0 snap to 0, even if they're a small change
+    #[test]
+    fn test_hysteresis_rest() {
+        let now = Instant::now();
+        let mut state = FilterState::new(0.12, now);
+        assert_eq!(state.apply(HYSTERESIS, 0.05, now), 0.0);
+        assert_eq!(state.apply(HYSTERESIS, -0.05, now), 0.0);
+    }
+
+    /// After one time constant, ~63% of a step has come through
+    #[test]
+    fn test_smoothing() {
+        let filter = AxisFilter {
+            time_constant_ms: 100.0,
+            hysteresis: 0.0,
+        };
+        let start = Instant::now();
+        let mut state = FilterState::new(0.0, start);
+        let value =
+            state.apply(filter, 1.0, start + Duration::from_millis(100));
+        assert!((value - 0.632).abs() < 0.001, "{}", value);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{
+    config::InputConfig,
+    input::{DeviceInfo, InputSource},
+};
+use anyhow::Context;
+use gilrs::{Axis, Button, Event, EventType, Gamepad, Gilrs, MappingSource};
+use log::{debug, info, warn};
+use std::{env, fs};
+
+/// Environment variable that gilrs reads extra SDL mappings from
+const SDL_MAPPINGS_VAR: &str = "SDL_GAMECONTROLLERCONFIG";
+
+/// Input source for physical gamepads, read via gilrs
+#[derive(Debug)]
+pub struct GamepadSource {
+    gil: Gilrs,
+}
+
+impl GamepadSource {
+    pub fn new(config: &InputConfig) -> anyhow::Result<Self> {
+        let mappings = load_mappings(config)?;
+        if !mappings.is_empty() {
+            info!("Loaded {} custom gamepad mapping(s)", mappings.len());
+            // gilrs adds mappings from its builder *before* its bundled
+            // database, so the bundled ones would win for any gamepad that's
+            // in both. Mappings from the environment are added last, so pass
+            // ours through there instead. Any mappings that were already in
+            // the environment are kept, but ours take priority.
+            let mut all_mappings =
+                env::var(SDL_MAPPINGS_VAR).unwrap_or_default();
+            for mapping in &mappings {
+                all_mappings.push('\n');
+                all_mappings.push_str(mapping);
+            }
+            env::set_var(SDL_MAPPINGS_VAR, all_mappings);
+        }
+
+        let gil = Gilrs::new()
+            .map_err(|err| anyhow::anyhow!("Initializing gilrs: {}", err))?;
+        for (_, gamepad) in gil.gamepads() {
+            check_mapping(&gamepad);
+        }
+        Ok(Self { gil })
+    }
+
+    /// Get a connected gamepad by its ID
+    fn gamepad(&self, device: usize) -> Option<Gamepad<'_>> {
+        self.gil
+            .gamepads()
+            .find(|&(id, _)| Into::<usize>::into(id) == device)
+            .map(|(_, gamepad)| gamepad)
+    }
+}
+
+impl InputSource for GamepadSource {
+    fn update(&mut self) {
+        // Draining the event queue is what keeps gilrs' cached gamepad state
+        // up to date
+        while let Some(Event { id, event, .. }) = self.gil.next_event() {
+            match event {
+                EventType::Connected => {
+                    let gamepad = self.gil.gamepad(id);
+                    info!(
+                        "Gamepad connected: {} (id={}, uuid={})",
+                        gamepad.name(),
+                        id,
+                        format_uuid(gamepad.uuid())
+                    );
+                    check_mapping(&gamepad);
+                }
+                EventType::Disconnected => {
+                    warn!(
+                        "Gamepad disconnected: {} (id={})",
+                        self.gil.gamepad(id).name(),
+                        id
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn devices(&self) -> Vec<(usize, DeviceInfo)> {
+        self.gil
+            .gamepads()
+            .map(|(id, gamepad)| {
+                (
+                    id.into(),
+                    DeviceInfo {
+                        name: gamepad.name().into(),
+                        uuid: format_uuid(gamepad.uuid()),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn axis_value(&self, device: usize, axis: Axis) -> Option<f32> {
+        let gamepad = self.gamepad(device)?;
+        let axis_data = gamepad.axis_data(axis)?;
+        Some(axis_data.value())
+    }
+
+    fn button_value(&self, device: usize, button: Button) -> Option<f32> {
+        let gamepad = self.gamepad(device)?;
+        let button_data = gamepad.button_data(button)?;
+        Some(button_data.value())
+    }
+}
+
+/// Format a gamepad UUID as a string of 32 lowercase hex digits. This is the
+/// same format SDL uses for its GUIDs.
+fn format_uuid(uuid: [u8; 16]) -> String {
+    uuid.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Warn if a gamepad has no mapping, because then its axes are probably wrong
+fn check_mapping(gamepad: &Gamepad<'_>) {
+    debug!(
+        "Gamepad {} mapping source: {:?}",
+        gamepad.name(),
+        gamepad.mapping_source()
+    );
+    if gamepad.mapping_source() == MappingSource::None {
+        warn!(
+            "Gamepad {} has no known mapping, so its axes may be wrong. Add \
+            an SDL mapping for UUID {} to the input config to fix this.",
+            gamepad.name(),
+            format_uuid(gamepad.uuid())
+        );
+    }
+}
+
+/// Collect all custom SDL mappings from the input config, including those in
+/// the mappings file (if any). Blank lines and comments are skipped.
+fn load_mappings(config: &InputConfig) -> anyhow::Result<Vec<String>> {
+    let mut mappings = config.mappings.clone();
+    if let Some(path) = &config.mappings_path {
+        let content = fs::read_to_string(path).with_context(|| {
+            format!("Reading gamepad mappings from {}", path)
+        })?;
+        mappings.extend(content.lines().map(String::from));
+    }
+
+    // Trim each line, so CRLF line endings don't end up in the mapping
+    for mapping in &mut mappings {
+        *mapping = mapping.trim().to_owned();
+    }
+    mappings.retain(|mapping| {
+        if mapping.is_empty() || mapping.starts_with('#') {
+            return false;
+        }
+        // Each mapping should start with a GUID, then a name. Anything else
+        // will be silently ignored by gilrs, so warn about it here.
+        let guid = mapping.split(',').next().unwrap_or_default();
+        let valid = guid.len() == 32
+            && guid.chars().all(|c| c.is_ascii_hexdigit())
+            && mapping.split(',').count() > 2;
+        if !valid {
+            warn!("Ignoring invalid gamepad mapping: {:?}", mapping);
+        }
+        valid
+    });
+    Ok(mappings)
+}
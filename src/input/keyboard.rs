@@ -0,0 +1,288 @@
+use crate::input::{DeviceInfo, InputSource};
+use anyhow::Context;
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    terminal,
+    tty::IsTty,
+};
+use gilrs::{Axis, Button};
+use log::{error, info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    time::{Duration, Instant},
+};
+
+/// UUID reported for the keyboard, so it can be selected like a gamepad
+const KEYBOARD_UUID: &str = "keyboard";
+
+/// Configuration for the terminal keyboard input source. When enabled, the
+/// keyboard shows up as a device named "Keyboard", which can be bound to a
+/// gamepad slot like any other gamepad.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct KeyboardConfig {
+    /// Read keys from the terminal. This puts the terminal in raw mode, so
+    /// only enable it when running interactively (e.g. over SSH). Only read
+    /// at startup.
+    pub enabled: bool,
+    /// Virtual axes, each driven by a pair of keys
+    pub axes: HashMap<Axis, KeyAxis>,
+    /// How fast an axis ramps toward ±1 while a key is held, in units/second
+    pub ramp_rate: f32,
+    /// How fast an axis returns to 0 once its keys are released, in
+    /// units/second
+    pub center_rate: f32,
+    /// Terminals don't report key releases, just repeated presses while a key
+    /// is held. A key is considered released once it hasn't repeated for this
+    /// long. Must be longer than the terminal's key repeat interval.
+    pub release_timeout_ms: u64,
+    /// Like [Self::release_timeout_ms], but for the first repeat after a key
+    /// is pressed, which terminals delay for longer
+    pub initial_release_timeout_ms: u64,
+}
+
+impl Default for KeyboardConfig {
+    fn default() -> Self {
+        let mut axes = HashMap::new();
+        axes.insert(
+            Axis::LeftStickY,
+            KeyAxis {
+                positive: "w".into(),
+                negative: "s".into(),
+            },
+        );
+        axes.insert(
+            Axis::RightStickY,
+            KeyAxis {
+                positive: "up".into(),
+                negative: "down".into(),
+            },
+        );
+        Self {
+            enabled: false,
+            axes,
+            ramp_rate: 2.0,
+            center_rate: 4.0,
+            release_timeout_ms: 100,
+            initial_release_timeout_ms: 600,
+        }
+    }
+}
+
+/// A virtual axis driven by two keys
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct KeyAxis {
+    /// Key that pushes the axis toward 1
+    pub positive: String,
+    /// Key that pushes the axis toward -1
+    pub negative: String,
+}
+
+/// Parse a key name from the config, e.g. "w" or "up"
+fn parse_key(name: &str) -> anyhow::Result<KeyCode> {
+    let key = match name.to_lowercase().as_str() {
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "space" => KeyCode::Char(' '),
+        "enter" => KeyCode::Enter,
+        "tab" => KeyCode::Tab,
+        name if name.chars().count() == 1 => {
+            KeyCode::Char(name.chars().next().unwrap())
+        }
+        _ => anyhow::bail!("Unknown key {:?}", name),
+    };
+    Ok(key)
+}
+
+/// A key that's currently held down (as far as we can tell)
+#[derive(Copy, Clone, Debug)]
+struct HeldKey {
+    /// Last time we got a press/repeat for this key
+    last_seen: Instant,
+    /// Has the key repeated since it was first pressed?
+    repeated: bool,
+}
+
+/// Input source that reads keys from the terminal, and turns them into
+/// virtual axis values. Axes ramp up while their key is held, and
+/// automatically return to center when released.
+#[derive(Debug)]
+pub struct KeyboardSource {
+    config: KeyboardConfig,
+    /// The (positive, negative) keys for each axis
+    keys: Vec<(Axis, KeyCode, KeyCode)>,
+    held: HashMap<KeyCode, HeldKey>,
+    values: HashMap<Axis, f32>,
+    last_update: Instant,
+    quit: bool,
+}
+
+impl KeyboardSource {
+    pub fn new(config: &KeyboardConfig) -> anyhow::Result<Self> {
+        let keys = config
+            .axes
+            .iter()
+            .map(|(&axis, key_axis)| {
+                Ok((
+                    axis,
+                    parse_key(&key_axis.positive)?,
+                    parse_key(&key_axis.negative)?,
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("Parsing keyboard axes")?;
+
+        anyhow::ensure!(
+            io::stdin().is_tty(),
+            "Keyboard input requires an interactive terminal"
+        );
+        terminal::enable_raw_mode().context("Enabling terminal raw mode")?;
+        info!(
+            "Keyboard input enabled with axes {:?}. Press Ctrl-C to quit.",
+            config.axes
+        );
+
+        Ok(Self {
+            config: config.clone(),
+            values: keys.iter().map(|&(axis, _, _)| (axis, 0.0)).collect(),
+            keys,
+            held: HashMap::new(),
+            last_update: Instant::now(),
+            quit: false,
+        })
+    }
+
+    /// Handle a single key press (or repeat)
+    fn handle_key(&mut self, key_event: KeyEvent, now: Instant) {
+        // Raw mode swallows Ctrl-C, so we have to handle it ourselves
+        if key_event.code == KeyCode::Char('c')
+            && key_event.modifiers.contains(KeyModifiers::CONTROL)
+        {
+            warn!("Ctrl-C pressed, shutting down");
+            self.quit = true;
+            return;
+        }
+
+        // Ignore shift/caps lock, so "W" still drives
+        let code = match key_event.code {
+            KeyCode::Char(c) => KeyCode::Char(c.to_ascii_lowercase()),
+            code => code,
+        };
+        self.held
+            .entry(code)
+            .and_modify(|held| {
+                held.last_seen = now;
+                held.repeated = true;
+            })
+            .or_insert(HeldKey {
+                last_seen: now,
+                repeated: false,
+            });
+    }
+
+    fn is_held(&self, key: KeyCode) -> bool {
+        self.held.contains_key(&key)
+    }
+}
+
+impl InputSource for KeyboardSource {
+    fn update(&mut self) {
+        let now = Instant::now();
+        let elapsed = (now - self.last_update).as_secs_f32();
+        self.last_update = now;
+
+        // Read all pending key events, without blocking
+        loop {
+            match event::poll(Duration::from_secs(0)) {
+                Ok(false) => break,
+                Ok(true) => match event::read() {
+                    Ok(Event::Key(key_event)) => {
+                        self.handle_key(key_event, now)
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        error!("Error reading keyboard input: {}", err);
+                        break;
+                    }
+                },
+                Err(err) => {
+                    error!("Error polling keyboard input: {}", err);
+                    break;
+                }
+            }
+        }
+
+        // Release any keys that have stopped repeating
+        let release_timeout =
+            Duration::from_millis(self.config.release_timeout_ms);
+        let initial_release_timeout =
+            Duration::from_millis(self.config.initial_release_timeout_ms);
+        self.held.retain(|_, held| {
+            let timeout = if held.repeated {
+                release_timeout
+            } else {
+                initial_release_timeout
+            };
+            now - held.last_seen < timeout
+        });
+
+        // Move each axis toward its target value
+        for &(axis, positive, negative) in &self.keys {
+            let target = match (self.is_held(positive), self.is_held(negative))
+            {
+                (true, false) => 1.0,
+                (false, true) => -1.0,
+                _ => 0.0,
+            };
+            let rate = if target == 0.0 {
+                self.config.center_rate
+            } else {
+                self.config.ramp_rate
+            };
+            let step = rate * elapsed;
+            let value = self.values.entry(axis).or_insert(0.0);
+            *value = if *value < target {
+                (*value + step).min(target)
+            } else {
+                (*value - step).max(target)
+            };
+        }
+    }
+
+    fn devices(&self) -> Vec<(usize, DeviceInfo)> {
+        vec![(
+            0,
+            DeviceInfo {
+                name: "Keyboard".into(),
+                uuid: KEYBOARD_UUID.into(),
+            },
+        )]
+    }
+
+    fn axis_value(&self, _device: usize, axis: Axis) -> Option<f32> {
+        self.values.get(&axis).copied()
+    }
+
+    fn button_value(&self, _device: usize, _button: Button) -> Option<f32> {
+        // Keys only drive axes
+        None
+    }
+
+    fn quit_requested(&self) -> bool {
+        self.quit
+    }
+}
+
+// Restore the terminal on drop, otherwise the user's shell will be unusable
+impl Drop for KeyboardSource {
+    fn drop(&mut self) {
+        if let Err(err) = terminal::disable_raw_mode() {
+            error!("Error disabling terminal raw mode: {}", err);
+        }
+    }
+}
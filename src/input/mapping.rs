@@ -0,0 +1,134 @@
+use crate::{
+    config::DriveMotorLocation,
+    input::{InputAxis, InputHandler, InputMapping, InputTrigger},
+    validation::ConfigProblems,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Tank drive, in which the two motors on one side of the robot (left or
+/// right) run in sync. One axis controls the left motors and another controls
+/// the right.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct TankMapping {
+    pub left_motor_axis: InputAxis,
+    pub right_motor_axis: InputAxis,
+}
+
+impl InputMapping for TankMapping {
+    fn motor_value(
+        &self,
+        handler: &InputHandler,
+        gamepad: Option<&str>,
+        motor: DriveMotorLocation,
+    ) -> Option<f32> {
+        // Map to an input axis, then read that
+        let axis = match motor {
+            DriveMotorLocation::FrontLeft => self.left_motor_axis,
+            DriveMotorLocation::FrontRight => self.right_motor_axis,
+            DriveMotorLocation::BackLeft => self.left_motor_axis,
+            DriveMotorLocation::BackRight => self.right_motor_axis,
+        };
+        handler.read_axis(gamepad?, axis)
+    }
+
+    fn validate(&self, path: &str, problems: &mut ConfigProblems) {
+        problems.check_input_axis(
+            &format!("{}.left_motor_axis", path),
+            self.left_motor_axis,
+        );
+        problems.check_input_axis(
+            &format!("{}.right_motor_axis", path),
+            self.right_motor_axis,
+        );
+    }
+}
+
+/// Racing-style drive. One trigger drives forward and the other in reverse,
+/// and they're combined into a signed throttle. A stick axis steers, by
+/// speeding up the motors on one side and slowing down the other.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RacingMapping {
+    pub forward_trigger: InputTrigger,
+    pub reverse_trigger: InputTrigger,
+    /// Positive values steer right
+    pub steering_axis: InputAxis,
+}
+
+impl InputMapping for RacingMapping {
+    fn motor_value(
+        &self,
+        handler: &InputHandler,
+        gamepad: Option<&str>,
+        motor: DriveMotorLocation,
+    ) -> Option<f32> {
+        let gamepad = gamepad?;
+        let throttle = handler.read_trigger(gamepad, self.forward_trigger)?
+            - handler.read_trigger(gamepad, self.reverse_trigger)?;
+        let steering = handler.read_axis(gamepad, self.steering_axis)?;
+        let value =
+            match motor {
+                DriveMotorLocation::FrontLeft
+                | DriveMotorLocation::BackLeft => throttle + steering,
+                DriveMotorLocation::FrontRight
+                | DriveMotorLocation::BackRight => throttle - steering,
+            };
+        Some(value.clamp(-1.0, 1.0))
+    }
+
+    fn validate(&self, path: &str, problems: &mut ConfigProblems) {
+        problems.check_trigger(
+            &format!("{}.forward_trigger", path),
+            self.forward_trigger,
+        );
+        problems.check_trigger(
+            &format!("{}.reverse_trigger", path),
+            self.reverse_trigger,
+        );
+        problems.check_input_axis(
+            &format!("{}.steering_axis", path),
+            self.steering_axis,
+        );
+    }
+}
+
+/// Set motor speeds manually. All values are [-1, 1]. Useful to set motor
+/// speeds from the HTTP API.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ManualMapping {
+    pub front_left: f32,
+    pub front_right: f32,
+    pub back_left: f32,
+    pub back_right: f32,
+}
+
+impl InputMapping for ManualMapping {
+    fn motor_value(
+        &self,
+        _: &InputHandler,
+        _: Option<&str>,
+        motor: DriveMotorLocation,
+    ) -> Option<f32> {
+        Some(match motor {
+            DriveMotorLocation::FrontLeft => self.front_left,
+            DriveMotorLocation::FrontRight => self.front_right,
+            DriveMotorLocation::BackLeft => self.back_left,
+            DriveMotorLocation::BackRight => self.back_right,
+        })
+    }
+
+    fn validate(&self, path: &str, problems: &mut ConfigProblems) {
+        for (name, speed) in &[
+            ("front_left", self.front_left),
+            ("front_right", self.front_right),
+            ("back_left", self.back_left),
+            ("back_right", self.back_right),
+        ] {
+            problems.check_speed(&format!("{}.{}", path, name), *speed);
+        }
+    }
+
+    fn uses_speed_gears(&self) -> bool {
+        false
+    }
+}
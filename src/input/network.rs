@@ -0,0 +1,208 @@
+use crate::input::{DeviceInfo, InputSource};
+use gilrs::{Axis, Button};
+use log::{info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Configuration for virtual joysticks connected over the network, via the
+/// `/joystick` page in the API. Each connected browser shows up as a device
+/// named "Web joystick (<address>)", which can be bound to a gamepad slot like
+/// any other gamepad.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// Serve the joystick page and accept connections. Only read at startup.
+    pub enabled: bool,
+    /// If a joystick doesn't send an update for this long, it's considered
+    /// disconnected and its axes are zeroed
+    pub timeout_ms: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout_ms: 500,
+        }
+    }
+}
+
+/// An update from a network joystick. Any axes/buttons that are omitted keep
+/// their previous values.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct JoystickMessage {
+    /// Axis values, in [-1, 1]
+    pub axes: HashMap<Axis, f32>,
+    /// Button values, in [0, 1]
+    pub buttons: HashMap<Button, f32>,
+}
+
+/// State of one connected network joystick
+#[derive(Clone, Debug)]
+struct NetworkJoystick {
+    name: String,
+    /// Identifies the browser, so calibration and selectors can treat it
+    /// like any other gamepad
+    uuid: String,
+    axes: HashMap<Axis, f32>,
+    buttons: HashMap<Button, f32>,
+    last_message: Instant,
+    /// Has this joystick gone quiet for longer than the timeout?
+    stale: bool,
+}
+
+#[derive(Debug, Default)]
+struct NetworkJoysticksInner {
+    next_id: usize,
+    joysticks: HashMap<usize, NetworkJoystick>,
+}
+
+/// All joysticks connected over the network. This is shared between the API,
+/// which writes updates as they arrive, and [NetworkSource], which reads them.
+/// It's only ever locked briefly, so a sync lock is fine.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkJoysticks(Arc<Mutex<NetworkJoysticksInner>>);
+
+impl NetworkJoysticks {
+    /// Register a new joystick, and get its ID. `device` is the ID that the
+    /// browser generated for itself (32 hex digits), which is used as the
+    /// joystick's UUID. Without one, the joystick gets a UUID that's only
+    /// unique to this connection.
+    pub fn connect(&self, address: &str, device: Option<&str>) -> usize {
+        let mut inner = self.0.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let name = format!("Web joystick ({})", address);
+        let uuid = match device {
+            Some(device)
+                if device.len() == 32
+                    && device.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                device.to_lowercase()
+            }
+            _ => format!("network-{}", id),
+        };
+        info!(
+            "Network joystick connected: {} (id={}, uuid={})",
+            name, id, uuid
+        );
+        inner.joysticks.insert(
+            id,
+            NetworkJoystick {
+                name,
+                uuid,
+                axes: HashMap::new(),
+                buttons: HashMap::new(),
+                last_message: Instant::now(),
+                stale: false,
+            },
+        );
+        id
+    }
+
+    /// Apply an update from a joystick
+    pub fn update(&self, id: usize, message: JoystickMessage) {
+        let mut inner = self.0.lock().unwrap();
+        if let Some(joystick) = inner.joysticks.get_mut(&id) {
+            if joystick.stale {
+                info!("Network joystick {} is back", joystick.name);
+                joystick.stale = false;
+            }
+            joystick.last_message = Instant::now();
+            for (axis, value) in message.axes {
+                joystick.axes.insert(axis, value.clamp(-1.0, 1.0));
+            }
+            for (button, value) in message.buttons {
+                joystick.buttons.insert(button, value.clamp(0.0, 1.0));
+            }
+        }
+    }
+
+    /// Remove a joystick, after its connection closes
+    pub fn disconnect(&self, id: usize) {
+        let mut inner = self.0.lock().unwrap();
+        if let Some(joystick) = inner.joysticks.remove(&id) {
+            warn!("Network joystick disconnected: {}", joystick.name);
+        }
+    }
+}
+
+/// Input source for virtual joysticks connected over the network
+#[derive(Debug)]
+pub struct NetworkSource {
+    joysticks: NetworkJoysticks,
+    timeout: Duration,
+}
+
+impl NetworkSource {
+    pub fn new(config: &NetworkConfig, joysticks: NetworkJoysticks) -> Self {
+        Self {
+            joysticks,
+            timeout: Duration::from_millis(config.timeout_ms),
+        }
+    }
+}
+
+impl InputSource for NetworkSource {
+    fn update(&mut self) {
+        // Zero out any joysticks that have gone quiet. They'll also stop being
+        // listed as devices, so their slots can fall back to something else.
+        let mut inner = self.joysticks.0.lock().unwrap();
+        for joystick in inner.joysticks.values_mut() {
+            if !joystick.stale && joystick.last_message.elapsed() > self.timeout
+            {
+                warn!("Network joystick {} timed out", joystick.name);
+                joystick.stale = true;
+                joystick.axes.clear();
+                joystick.buttons.clear();
+            }
+        }
+    }
+
+    fn devices(&self) -> Vec<(usize, DeviceInfo)> {
+        let inner = self.joysticks.0.lock().unwrap();
+        let mut devices: Vec<_> = inner
+            .joysticks
+            .iter()
+            .filter(|(_, joystick)| !joystick.stale)
+            .map(|(&id, joystick)| {
+                (
+                    id,
+                    DeviceInfo {
+                        name: joystick.name.clone(),
+                        uuid: joystick.uuid.clone(),
+                    },
+                )
+            })
+            .collect();
+        // Keep the order stable, so index selectors are predictable
+        devices.sort_by_key(|&(id, _)| id);
+        devices
+    }
+
+    fn axis_value(&self, device: usize, axis: Axis) -> Option<f32> {
+        let inner = self.joysticks.0.lock().unwrap();
+        let joystick = inner.joysticks.get(&device)?;
+        if joystick.stale {
+            return None;
+        }
+        // The page always sends every axis it has, so a missing one just
+        // hasn't been touched yet
+        Some(joystick.axes.get(&axis).copied().unwrap_or(0.0))
+    }
+
+    fn button_value(&self, device: usize, button: Button) -> Option<f32> {
+        let inner = self.joysticks.0.lock().unwrap();
+        let joystick = inner.joysticks.get(&device)?;
+        if joystick.stale {
+            return None;
+        }
+        Some(joystick.buttons.get(&button).copied().unwrap_or(0.0))
+    }
+}
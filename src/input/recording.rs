@@ -0,0 +1,428 @@
+use crate::input::{DeviceId, DeviceInfo, InputSource, AXES, BUTTONS};
+use anyhow::Context;
+use gilrs::{Axis, Button};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    time::{Duration, Instant},
+};
+
+/// The raw state of all connected input devices at one point in time. A
+/// recording is a file of these, one JSON object per line.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InputFrame {
+    /// Time since the start of the recording
+    pub time_ms: u64,
+    pub devices: Vec<DeviceFrame>,
+}
+
+/// The raw state of one input device
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeviceFrame {
+    pub id: DeviceId,
+    pub name: String,
+    pub uuid: String,
+    pub axes: HashMap<Axis, f32>,
+    pub buttons: HashMap<Button, f32>,
+}
+
+impl DeviceFrame {
+    /// Capture the current state of a device
+    pub fn capture(
+        id: DeviceId,
+        info: DeviceInfo,
+        source: &dyn InputSource,
+    ) -> Self {
+        Self {
+            id,
+            name: info.name,
+            uuid: info.uuid,
+            axes: AXES
+                .iter()
+                .filter_map(|&axis| {
+                    Some((axis, source.axis_value(id.device, axis)?))
+                })
+                .collect(),
+            buttons: BUTTONS
+                .iter()
+                .filter_map(|&button| {
+                    Some((button, source.button_value(id.device, button)?))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Records raw input to a file, so it can be replayed later. A frame is only
+/// written when something changes, to keep the file size down.
+#[derive(Debug)]
+pub struct InputRecorder {
+    writer: BufWriter<File>,
+    start: Instant,
+    last_devices: Option<Vec<DeviceFrame>>,
+}
+
+impl InputRecorder {
+    pub fn new(path: &str) -> anyhow::Result<Self> {
+        info!("Recording input to {}", path);
+        let file = File::create(path)
+            .with_context(|| format!("Creating input recording {}", path))?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            start: Instant::now(),
+            last_devices: None,
+        })
+    }
+
+    /// Record the current state of all devices, if it changed since the last
+    /// frame
+    pub fn record(&mut self, devices: Vec<DeviceFrame>) {
+        if self.last_devices.as_ref() == Some(&devices) {
+            return;
+        }
+
+        let frame = InputFrame {
+            time_ms: self.start.elapsed().as_millis() as u64,
+            devices,
+        };
+        let result = serde_json::to_writer(&mut self.writer, &frame)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| {
+                self.writer.write_all(b"\n")?;
+                // Flush every frame, so nothing is lost if we crash
+                self.writer.flush()?;
+                Ok(())
+            });
+        if let Err(err) = result {
+            error!("Error writing input recording: {}", err);
+        }
+        self.last_devices = Some(frame.devices);
+    }
+}
+
+/// How a replay moves forward
+#[derive(Debug)]
+enum ReplayClock {
+    /// Follow the real clock, from when the replay started
+    RealTime(Instant),
+    /// Move forward by a fixed step on each update
+    Stepped { step: Duration, elapsed: Duration },
+}
+
+/// Input source that replays a recording made by [InputRecorder], in place of
+/// all real input devices. Devices appear and disappear just as they did
+/// during recording, so slot selectors, calibration and input mappings all
+/// behave the same way they did originally. Once the replay ends, the robot
+/// is asked to shut down.
+#[derive(Debug)]
+pub struct ReplaySource {
+    frames: Vec<InputFrame>,
+    /// Recorded device IDs, indexed by the device ID used in this source
+    device_ids: Vec<DeviceId>,
+    clock: ReplayClock,
+    /// Index of the current frame. None if we haven't reached the first one
+    current: Option<usize>,
+    finished: bool,
+}
+
+impl ReplaySource {
+    /// Load a recording from a file. With a `step`, the replay moves forward
+    /// by that much on each update, rather than following the clock.
+    pub fn load(path: &str, step: Option<Duration>) -> anyhow::Result<Self> {
+        info!("Replaying input from {}", path);
+        let file = File::open(path)
+            .with_context(|| format!("Opening input recording {}", path))?;
+        let frames = BufReader::new(file)
+            .lines()
+            .enumerate()
+            .map(|(i, line)| {
+                let frame =
+                    serde_json::from_str(&line?).with_context(|| {
+                        format!("Parsing {} line {}", path, i + 1)
+                    })?;
+                Ok(frame)
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self::new(frames, step))
+    }
+
+    /// Create a replay of the given frames, starting now
+    pub fn new(frames: Vec<InputFrame>, step: Option<Duration>) -> Self {
+        let mut device_ids = Vec::new();
+        for frame in &frames {
+            for device in &frame.devices {
+                if !device_ids.contains(&device.id) {
+                    device_ids.push(device.id);
+                }
+            }
+        }
+        Self {
+            frames,
+            device_ids,
+            clock: match step {
+                None => ReplayClock::RealTime(Instant::now()),
+                Some(step) => ReplayClock::Stepped {
+                    step,
+                    elapsed: Duration::ZERO,
+                },
+            },
+            current: None,
+            finished: false,
+        }
+    }
+
+    /// Move the replay to the given time since its start
+    fn advance_to(&mut self, elapsed: Duration) {
+        let elapsed_ms = elapsed.as_millis() as u64;
+        let next = self.current.map_or(0, |i| i + 1);
+        for i in next..self.frames.len() {
+            if self.frames[i].time_ms > elapsed_ms {
+                break;
+            }
+            self.current = Some(i);
+        }
+
+        // The replay is over once we've been on the last frame for a tick
+        let last_time = self.frames.last().map_or(0, |frame| frame.time_ms);
+        if !self.finished && elapsed_ms > last_time {
+            info!("Input replay finished");
+            self.finished = true;
+        }
+    }
+
+    /// Get the state of a device in the current frame
+    fn device(&self, device: usize) -> Option<&DeviceFrame> {
+        if self.finished {
+            return None;
+        }
+        let id = self.device_ids.get(device)?;
+        let frame = &self.frames[self.current?];
+        frame.devices.iter().find(|frame| &frame.id == id)
+    }
+}
+
+impl InputSource for ReplaySource {
+    fn update(&mut self) {
+        let elapsed = match &mut self.clock {
+            ReplayClock::RealTime(start) => start.elapsed(),
+            ReplayClock::Stepped { step, elapsed } => {
+                let current = *elapsed;
+                *elapsed += *step;
+                current
+            }
+        };
+        self.advance_to(elapsed);
+    }
+
+    fn devices(&self) -> Vec<(usize, DeviceInfo)> {
+        (0..self.device_ids.len())
+            .filter_map(|device| {
+                let frame = self.device(device)?;
+                Some((
+                    device,
+                    DeviceInfo {
+                        name: frame.name.clone(),
+                        uuid: frame.uuid.clone(),
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    fn axis_value(&self, device: usize, axis: Axis) -> Option<f32> {
+        self.device(device)?.axes.get(&axis).copied()
+    }
+
+    fn button_value(&self, device: usize, button: Button) -> Option<f32> {
+        self.device(device)?.buttons.get(&button).copied()
+    }
+
+    fn quit_requested(&self) -> bool {
+        self.finished
+    }
+
+    fn elapsed(&self) -> Option<Duration> {
+        match self.clock {
+            ReplayClock::RealTime(_) => None,
+            // The last update was one step ago
+            ReplayClock::Stepped { step, elapsed } => {
+                Some(elapsed.saturating_sub(step))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        calibration::CalibrationStore,
+        config::{DriveMotorLocation, InputConfig},
+        input::InputHandler,
+        motors::{MotorChannel, MotorController, SimulatedMotors},
+    };
+    use serde_json::{json, Value};
+    use std::sync::{Arc, RwLock};
+
+    /// One gamepad: at rest, then sticks and triggers moved at 100ms, moved
+    /// again at 200ms, and back at rest at 300ms
+    const FIXTURE: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/drive.jsonl");
+    const STEP_MS: u64 = 50;
+    /// Channel for each motor, in the same order as [DriveMotorLocation::ALL]
+    const CHANNELS: [MotorChannel; 4] = [
+        MotorChannel::Motor1,
+        MotorChannel::Motor2,
+        MotorChannel::Motor3,
+        MotorChannel::Motor4,
+    ];
+
+    /// Replay the fixture through a drive mapping into simulated motors, the
+    /// same way the main loop does. Returns the speed of each motor (front
+    /// left, front right, back left, back right) after each step, until the
+    /// replay ends.
+    fn replay(drive: Value) -> Vec<[f32; 4]> {
+        let config: InputConfig = serde_json::from_value(json!({
+            "replay_path": FIXTURE,
+            "replay_step_ms": STEP_MS,
+            "drive": drive,
+        }))
+        .unwrap();
+        let calibration = Arc::new(RwLock::new(
+            CalibrationStore::load("/nonexistent/calibration.json").unwrap(),
+        ));
+        let mut handler =
+            InputHandler::new(&config, calibration, None).unwrap();
+        let mut motors = SimulatedMotors::default();
+
+        let mut steps = Vec::new();
+        loop {
+            handler.update(&config);
+            for (&motor, &channel) in
+                DriveMotorLocation::ALL.iter().zip(&CHANNELS)
+            {
+                let speed = handler.motor_value(&config, motor).unwrap_or(0.0);
+                motors.set_speed(channel, speed).unwrap();
+            }
+            steps.push(CHANNELS.map(|channel| motors.speed(channel)));
+            if handler.quit_requested() {
+                return steps;
+            }
+        }
+    }
+
+    /// Get the step at a time in the replay
+    fn at(steps: &[[f32; 4]], time_ms: u64) -> [f32; 4] {
+        steps[(time_ms / STEP_MS) as usize]
+    }
+
+    fn tank() -> Value {
+        json!({
+            "type": "tank",
+            "left_motor_axis": {"axis": "LeftStickY", "transformation": "linear"},
+            "right_motor_axis": {"axis": "RightStickY", "transformation": "linear"},
+        })
+    }
+
+    #[test]
+    fn test_tank() {
+        let steps = replay(tank());
+        assert_eq!(at(&steps, 0), [0.0; 4]);
+        assert_eq!(at(&steps, 100), [1.0, -0.5, 1.0, -0.5]);
+        assert_eq!(at(&steps, 150), [1.0, -0.5, 1.0, -0.5]);
+        assert_eq!(at(&steps, 200), [-0.5, 0.5, -0.5, 0.5]);
+        assert_eq!(at(&steps, 300), [0.0; 4]);
+    }
+
+    #[test]
+    fn test_racing() {
+        let steps = replay(json!({
+            "type": "racing",
+            "forward_trigger": {"button": "RightTrigger2"},
+            "reverse_trigger": {"button": "LeftTrigger2"},
+            "steering_axis": {"axis": "LeftStickX", "transformation": "linear"},
+        }));
+        assert_eq!(at(&steps, 0), [0.0; 4]);
+        // Half throttle, steering right
+        assert_eq!(at(&steps, 100), [0.75, 0.25, 0.75, 0.25]);
+        // Full reverse, steering left. The left side is clamped.
+        assert_eq!(at(&steps, 200), [-1.0, -0.5, -1.0, -0.5]);
+        assert_eq!(at(&steps, 300), [0.0; 4]);
+    }
+
+    /// Trigger axes rest at 0, so an axis that hasn't moved doesn't drive
+    #[test]
+    fn test_racing_axis_trigger() {
+        let steps = replay(json!({
+            "type": "racing",
+            "forward_trigger": {"axis": "RightStickX"},
+            "reverse_trigger": {"button": "LeftTrigger2"},
+            "steering_axis": {"axis": "LeftStickX", "transformation": "linear"},
+        }));
+        assert_eq!(at(&steps, 0), [0.0; 4]);
+        // Steering only
+        assert_eq!(at(&steps, 100), [0.25, -0.25, 0.25, -0.25]);
+        assert_eq!(at(&steps, 300), [0.0; 4]);
+    }
+
+    /// Bipolar trigger axes rest at -1, so 0 is half pressed
+    #[test]
+    fn test_racing_bipolar_trigger() {
+        let steps = replay(json!({
+            "type": "racing",
+            "forward_trigger": {"axis": "RightStickX", "bipolar": true},
+            "reverse_trigger": {"button": "LeftTrigger2"},
+            "steering_axis": {"axis": "LeftStickX", "transformation": "linear"},
+        }));
+        assert_eq!(at(&steps, 0), [0.5; 4]);
+        assert_eq!(at(&steps, 100), [0.75, 0.25, 0.75, 0.25]);
+    }
+
+    #[test]
+    fn test_manual() {
+        let steps = replay(json!({
+            "type": "manual",
+            "front_left": 0.1,
+            "front_right": 0.2,
+            "back_left": -0.3,
+            "back_right": -0.4,
+        }));
+        // Input is ignored entirely
+        for step in steps {
+            assert_eq!(step, [0.1, 0.2, -0.3, -0.4]);
+        }
+    }
+
+    /// Once the replay ends, its gamepad disconnects, so input mappings stop
+    /// the motors
+    #[test]
+    fn test_replay_end() {
+        let steps = replay(tank());
+        // The last frame is at 300ms, and the replay ends on the step after
+        assert_eq!(steps.len(), (300 / STEP_MS + 2) as usize);
+        assert_eq!(steps.last(), Some(&[0.0; 4]));
+    }
+
+    /// A stepped replay is timed by its own clock, so even time-based filters
+    /// come out the same every time
+    #[test]
+    fn test_deterministic() {
+        let filtered = json!({
+            "axis": "LeftStickY",
+            "transformation": "linear",
+            "filter": {"time_constant_ms": 100.0, "hysteresis": 0.0},
+        });
+        let drive = json!({
+            "type": "tank",
+            "left_motor_axis": filtered,
+            "right_motor_axis": filtered,
+        });
+        let steps = replay(drive.clone());
+        assert_eq!(steps, replay(drive));
+        // The filter smooths out the jump at 100ms
+        let value = at(&steps, 100)[0];
+        assert!(0.0 < value && value < 1.0, "{}", value);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::{
+    config::DriveMotorLocation,
+    input::{InputHandler, InputSnapshot},
+    schema::ButtonName,
+};
+use gilrs::Button;
+use log::{info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Configuration for drive macros, i.e. canned maneuvers that the robot runs
+/// on its own. A running macro overrides the drive input mapping, and is
+/// cancelled as soon as anything on the controlling gamepad is touched, the
+/// stop button is pressed, or control passes to another gamepad.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct MacroConfig {
+    /// All available macros, by name
+    pub sequences: HashMap<String, DriveMacro>,
+    /// Button on the controlling gamepad that cancels the running macro
+    #[schemars(with = "Option<ButtonName>")]
+    pub stop_button: Option<Button>,
+    /// Moving any stick or trigger, or pressing any button, on the
+    /// controlling gamepad further than this (in [0, 1]) cancels the running
+    /// macro. Buttons that start macros don't count.
+    pub cancel_threshold: f32,
+}
+
+impl Default for MacroConfig {
+    fn default() -> Self {
+        Self {
+            sequences: HashMap::new(),
+            stop_button: Some(Button::East),
+            cancel_threshold: 0.2,
+        }
+    }
+}
+
+/// A sequence of drive commands, run one after another
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct DriveMacro {
+    /// Button on the controlling gamepad that starts this macro
+    #[serde(default)]
+    #[schemars(with = "Option<ButtonName>")]
+    pub button: Option<Button>,
+    pub steps: Vec<MacroStep>,
+}
+
+/// One drive command in a macro, held for a fixed time. Speeds are [-1, 1],
+/// and aren't affected by the speed gear.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct MacroStep {
+    /// Speed for both left motors
+    pub left: f32,
+    /// Speed for both right motors
+    pub right: f32,
+    pub duration_ms: u64,
+}
+
+/// A request to start or stop a macro, from outside the main loop
+#[derive(Clone, Debug)]
+enum MacroRequest {
+    Run(String),
+    Stop,
+}
+
+/// Macro requests from the API. This is shared between the API, which writes
+/// requests, and [MacroRunner], which handles them once per loop. It's only
+/// ever locked briefly, so a sync lock is fine.
+#[derive(Clone, Debug, Default)]
+pub struct MacroRequests(Arc<Mutex<Option<MacroRequest>>>);
+
+impl MacroRequests {
+    /// Ask for a macro to be run. This replaces any pending request.
+    pub fn run(&self, name: String) {
+        *self.0.lock().unwrap() = Some(MacroRequest::Run(name));
+    }
+
+    /// Ask for the running macro to be cancelled
+    pub fn stop(&self) {
+        *self.0.lock().unwrap() = Some(MacroRequest::Stop);
+    }
+
+    fn take(&self) -> Option<MacroRequest> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// The running macro, as reported via the API
+#[derive(Clone, Debug, Serialize)]
+pub struct MacroStatus {
+    pub name: String,
+    /// Index of the current step
+    pub step: usize,
+    /// Time since the macro started
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug)]
+struct RunningMacro {
+    name: String,
+    steps: Vec<MacroStep>,
+    start: Instant,
+    /// The controlling gamepad's input when the macro started, or None if no
+    /// gamepad was in control
+    origin: Option<InputSnapshot>,
+}
+
+impl RunningMacro {
+    /// Get the current step and its index, or None if the macro is over
+    fn current_step(&self) -> Option<(usize, MacroStep)> {
+        let elapsed = self.start.elapsed();
+        let mut end = Duration::default();
+        self.steps.iter().copied().enumerate().find(|(_, step)| {
+            end += Duration::from_millis(step.duration_ms);
+            elapsed < end
+        })
+    }
+}
+
+/// Starts, runs and cancels macros. Should be updated once per loop.
+#[derive(Debug)]
+pub struct MacroRunner {
+    requests: MacroRequests,
+    running: Option<RunningMacro>,
+    /// Buttons that were held on the last update, so each press only
+    /// triggers once
+    held_buttons: HashSet<Button>,
+}
+
+impl MacroRunner {
+    pub fn new(requests: MacroRequests) -> Self {
+        Self {
+            requests,
+            running: None,
+            held_buttons: HashSet::new(),
+        }
+    }
+
+    /// Handle any API requests and button presses, and cancel the running
+    /// macro if the user has taken over
+    pub fn update(
+        &mut self,
+        config: &MacroConfig,
+        input_handler: &InputHandler,
+    ) {
+        let gamepad = input_handler.controlling_slot();
+        let held_buttons: HashSet<Button> = config
+            .sequences
+            .values()
+            .map(|drive_macro| drive_macro.button)
+            .chain(std::iter::once(config.stop_button))
+            .flatten()
+            .filter(|&button| {
+                gamepad.is_some_and(|gamepad| {
+                    input_handler.is_button_pressed(gamepad, button)
+                })
+            })
+            .collect();
+        let is_pressed = |button: Option<Button>| {
+            button.is_some_and(|button| {
+                held_buttons.contains(&button)
+                    && !self.held_buttons.contains(&button)
+            })
+        };
+
+        let mut stop = is_pressed(config.stop_button);
+        let mut start = config
+            .sequences
+            .iter()
+            .find(|(_, drive_macro)| is_pressed(drive_macro.button))
+            .map(|(name, _)| name.clone());
+        match self.requests.take() {
+            Some(MacroRequest::Run(name)) => start = Some(name),
+            Some(MacroRequest::Stop) => stop = true,
+            None => {}
+        }
+        self.held_buttons = held_buttons;
+
+        let snapshot =
+            gamepad.and_then(|gamepad| input_handler.snapshot(gamepad));
+        if let Some(running) = &self.running {
+            // Buttons that start macros switch to another macro instead
+            let ignored: HashSet<Button> = config
+                .sequences
+                .values()
+                .filter_map(|drive_macro| drive_macro.button)
+                .collect();
+            let taken_over = match (&running.origin, &snapshot) {
+                (None, None) => false,
+                (Some(origin), Some(snapshot)) => {
+                    snapshot.device() != origin.device()
+                        || snapshot.moved_since(
+                            origin,
+                            config.cancel_threshold,
+                            &ignored,
+                        )
+                }
+                // Control was lost or taken
+                _ => true,
+            };
+            if stop || taken_over {
+                info!("Cancelled macro {:?}", running.name);
+                self.running = None;
+                return;
+            }
+            if running.current_step().is_none() {
+                info!("Finished macro {:?}", running.name);
+                self.running = None;
+            }
+        }
+
+        // Stopping takes priority over starting
+        if stop {
+            return;
+        }
+        if let Some(name) = start {
+            match config.sequences.get(&name) {
+                None => warn!("Unknown macro {:?}", name),
+                Some(drive_macro) => {
+                    info!("Starting macro {:?}", name);
+                    self.running = Some(RunningMacro {
+                        name,
+                        steps: drive_macro.steps.clone(),
+                        start: Instant::now(),
+                        origin: snapshot,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Get the speed for a motor from the running macro. Returns None if no
+    /// macro is running, in which case normal input should be used.
+    pub fn motor_value(&self, motor: DriveMotorLocation) -> Option<f32> {
+        let (_, step) = self.running.as_ref()?.current_step()?;
+        Some(match motor {
+            DriveMotorLocation::FrontLeft | DriveMotorLocation::BackLeft => {
+                step.left
+            }
+            DriveMotorLocation::FrontRight | DriveMotorLocation::BackRight => {
+                step.right
+            }
+        })
+    }
+
+    /// Get the state of the running macro, for the API
+    pub fn status(&self) -> Option<MacroStatus> {
+        let running = self.running.as_ref()?;
+        let (step, _) = running.current_step()?;
+        Some(MacroStatus {
+            name: running.name.clone(),
+            step,
+            elapsed_ms: running.start.elapsed().as_millis() as u64,
+        })
+    }
+}
@@ -1,49 +1,198 @@
 mod api;
+mod auth;
+mod calibration;
+mod cli;
 mod config;
+mod history;
 mod input;
+mod macros;
+mod migrate;
 mod motors;
+mod persist;
+mod profiles;
+mod schema;
 mod sensors;
+mod speed;
+mod status;
+mod tls;
+mod validation;
+mod watch;
 
 use crate::{
     api::Api,
-    config::{DriveMotorLocation, RobotConfig},
-    input::InputHandler,
-    motors::MotorHat,
+    calibration::CalibrationStore,
+    cli::{Args, Command},
+    config::{
+        ConfigSources, DriveMotorLocation, MotorHardwareConfig, RobotConfig,
+    },
+    history::ConfigHistory,
+    input::{InputHandler, NetworkJoysticks},
+    macros::{MacroRequests, MacroRunner},
+    motors::{MotorController, SimulatedMotors},
+    profiles::ProfileSwitcher,
+    speed::SpeedControl,
+    status::RobotStatus,
 };
 use anyhow::Context;
 use async_std::sync::RwLock;
 use env_logger::Env;
-use std::{env, sync::Arc};
+use std::{
+    borrow::Cow,
+    process,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use structopt::StructOpt;
+
+/// The base config with its active profile applied. Resolving a profile
+/// means a deep merge, so it's only redone when the base config changes.
+struct ResolvedConfig {
+    /// The latest config revision when this was resolved. Every change to the
+    /// base config is recorded in the history, except for switching profiles
+    /// from a gamepad.
+    revision: Option<u64>,
+    /// The profile that was applied
+    profile: Option<String>,
+    config: RobotConfig,
+}
+
+impl ResolvedConfig {
+    /// Apply the active profile to the base config, if the base config has
+    /// changed since it was last resolved. `revision` is the latest revision
+    /// in the config history. Returns true if the profile was switched.
+    fn update(
+        &mut self,
+        base_config: &RobotConfig,
+        revision: Option<u64>,
+    ) -> bool {
+        if revision == self.revision && base_config.profile == self.profile {
+            return false;
+        }
 
-const DEFAULT_CONFIG_PATH: &str = "./config/default.toml";
+        // Validation makes sure the profile exists and applies cleanly, so
+        // this shouldn't fail
+        self.config = base_config
+            .resolve_profile()
+            .map(Cow::into_owned)
+            .unwrap_or_else(|err| {
+                log::error!("Error applying profile: {:?}", err);
+                base_config.clone()
+            });
+        self.revision = revision;
+        let switched = base_config.profile != self.profile;
+        self.profile = base_config.profile.clone();
+        switched
+    }
+}
 
 /// Main Robot struct. Handles initialization and operation of all robotic
 /// activities, as well as processing user input.
 // TODO fix debug derive
 // #[derive(Debug)]
 struct Robot {
+    config_sources: ConfigSources,
     config: Arc<RwLock<RobotConfig>>,
+    config_history: Arc<Mutex<ConfigHistory>>,
+    resolved: ResolvedConfig,
+    status: Arc<RwLock<RobotStatus>>,
     input_handler: InputHandler,
-    drive_motors: MotorHat,
+    speed: SpeedControl,
+    macros: MacroRunner,
+    profile_switcher: ProfileSwitcher,
+    drive_motors: Box<dyn MotorController>,
+    /// The config that [Self::drive_motors] was initialized from. None if
+    /// reinitializing failed, in which case the motors are simulated (and
+    /// the real ones stopped) until the config changes.
+    motor_hardware: Option<MotorHardwareConfig>,
+    /// Motor hardware config that last failed to initialize, so it isn't
+    /// retried on every loop
+    failed_motor_hardware: Option<MotorHardwareConfig>,
     api: Api,
 }
 
 impl Robot {
-    pub fn new(config: RobotConfig) -> anyhow::Result<Self> {
+    pub fn new(
+        config_sources: ConfigSources,
+        config: RobotConfig,
+    ) -> anyhow::Result<Self> {
+        // Hardware is initialized with the active profile applied. The
+        // profile can change later, so the unresolved config is what's shared.
+        let resolved = config.resolve_profile()?;
+
+        let calibration = Arc::new(std::sync::RwLock::new(
+            CalibrationStore::load(&resolved.input.calibration_path)
+                .context("Loading gamepad calibration")?,
+        ));
+
+        // Joysticks connected over the network are fed in through the API
+        let network_joysticks = if resolved.input.network.enabled {
+            Some(NetworkJoysticks::default())
+        } else {
+            None
+        };
+
         // Initialize hardware interfaces
-        let input_handler = InputHandler::new();
-        let drive_motors =
-            MotorHat::new(&config).context("Initializing drive motors")?;
+        let input_handler = InputHandler::new(
+            &resolved.input,
+            Arc::clone(&calibration),
+            network_joysticks.clone(),
+        )
+        .context("Initializing input")?;
+        let speed = SpeedControl::new(&resolved.input.speed);
+        // Macros can be started from the API too
+        let macro_requests = MacroRequests::default();
+        let macros = MacroRunner::new(macro_requests.clone());
+        let drive_motors = motors::init_drive_motors(&resolved)?;
+        let motor_hardware = resolved.motor_hardware();
+        let resolved = resolved.into_owned();
+
+        // API tokens are only read at startup
+        let tokens = config
+            .api
+            .tokens_path
+            .as_deref()
+            .map(auth::load_tokens)
+            .transpose()
+            .context("Loading API tokens")?;
+
+        // Every config change is recorded, starting with the initial config
+        let mut config_history = ConfigHistory::load(&config.api);
+        config_history.record(&config, None, "Loaded at startup");
+        let resolved = ResolvedConfig {
+            revision: config_history.latest().map(|revision| revision.id),
+            profile: config.profile.clone(),
+            config: resolved,
+        };
+        let config_history = Arc::new(Mutex::new(config_history));
 
         // Start an HTTP API to allow reading motor state/updating config
         // Wrap the config in a rw lock so we can mutate it from the API
         let config = Arc::new(RwLock::new(config));
-        let api = Api::new(Arc::clone(&config));
+        let status = Arc::new(RwLock::new(RobotStatus::default()));
+        let mut api = Api::new(
+            config_sources.clone(),
+            Arc::clone(&config),
+            Arc::clone(&config_history),
+            Arc::clone(&status),
+            calibration,
+            network_joysticks,
+            macro_requests,
+        );
+        api.set_tokens(tokens);
 
         Ok(Self {
+            config_sources,
             config,
+            config_history,
+            resolved,
+            status,
             input_handler,
+            speed,
+            macros,
+            profile_switcher: ProfileSwitcher::default(),
             drive_motors,
+            motor_hardware: Some(motor_hardware),
+            failed_motor_hardware: None,
             api,
         })
     }
@@ -61,21 +210,106 @@ impl Robot {
             }
         });
 
+        // Reload the config whenever the file changes
+        if let Err(err) = watch::watch_config(
+            self.config_sources,
+            Arc::clone(&self.config),
+            Arc::clone(&self.config_history),
+        ) {
+            log::error!("Error watching config files: {:?}", err);
+        }
+
         loop {
             // Grab the config lock. We intentionally hold it for the whole
             // iteration so a write can't interrupt the loop mid-iteration
-            let config = self.config.read().await;
+            let base_config = self.config.read().await;
+            let revision = self
+                .config_history
+                .lock()
+                .unwrap()
+                .latest()
+                .map(|revision| revision.id);
+            if self.resolved.update(&base_config, revision) {
+                // The new profile may have different gears
+                self.speed =
+                    SpeedControl::new(&self.resolved.config.input.speed);
+            }
+            let config = &self.resolved.config;
+
+            // Process gamepad events. This handles hot-plugging, and will
+            // drop any gamepad that's been disconnected, so the drive stops
+            self.input_handler.update(&config.input);
+            if self.input_handler.quit_requested() {
+                // Returning drops the robot, which turns off all the motors
+                log::info!("Stopping robot loop");
+                break;
+            }
+            self.speed.update(
+                &config.input.speed,
+                &self.input_handler,
+                self.input_handler.controlling_slot(),
+            );
+            self.macros
+                .update(&config.input.macros, &self.input_handler);
+            let switch_profile =
+                self.profile_switcher.update(config, &self.input_handler);
+            {
+                let mut status = self.status.write().await;
+                status.gamepads =
+                    self.input_handler.connection_states(&config.input);
+                status.control = self.input_handler.control_status();
+                status.speed = self.speed.status(&config.input.speed);
+                status.running_macro = self.macros.status();
+            }
+
+            // If the config changed the motor hardware, reinitialize it
+            let motor_hardware = config.motor_hardware();
+            if Some(&motor_hardware) != self.motor_hardware.as_ref()
+                && Some(&motor_hardware) != self.failed_motor_hardware.as_ref()
+            {
+                log::info!("Motor hardware config changed, reinitializing");
+                // The old controller turns its board off when dropped, so it
+                // has to go before the new one sets the board up
+                self.drive_motors = Box::new(SimulatedMotors::default());
+                self.motor_hardware = None;
+                match motors::init_drive_motors(config) {
+                    Ok(drive_motors) => {
+                        self.drive_motors = drive_motors;
+                        self.motor_hardware = Some(motor_hardware);
+                        self.failed_motor_hardware = None;
+                    }
+                    Err(err) => {
+                        log::error!(
+                            "Error reinitializing drive motors, they'll stay \
+                            stopped until the config changes: {:?}",
+                            err
+                        );
+                        self.failed_motor_hardware = Some(motor_hardware);
+                    }
+                }
+            }
 
-            // Try to connect to a gamepad. If we already have one
-            // connected, this won't do anything. This allows hot-plugging
-            self.input_handler.init_gamepad();
+            // Scale every motor by the active speed gear, unless the mapping
+            // sets speeds directly
+            let speed_scale = if config.input.drive.mapping().uses_speed_gears()
+            {
+                self.speed.scale(&config.input.speed)
+            } else {
+                1.0
+            };
 
-            // Set speed for each drive motor based on the user input
+            // Set speed for each drive motor based on the user input, unless
+            // a macro is running
             for &motor in DriveMotorLocation::ALL {
-                let speed = self
-                    .input_handler
-                    .motor_value(config.input.drive, motor)
-                    .unwrap_or(0.0);
+                let speed = match self.macros.motor_value(motor) {
+                    Some(speed) => speed,
+                    None => {
+                        self.input_handler
+                            .motor_value(&config.input, motor)
+                            .unwrap_or(0.0)
+                            * speed_scale
+                    }
+                };
                 // Map the drive motor position to a motor channel #
                 match config.drive.motors.get(&motor) {
                     Some(&motor_channel) => {
@@ -95,25 +329,61 @@ impl Robot {
                     }
                 }
             }
+
+            // Switching profiles needs the write lock, so do it last. This
+            // isn't persisted, so it's reset if the config file is reloaded.
+            if let Some(profile) = switch_profile {
+                drop(base_config);
+                self.config.write().await.profile = profile;
+            }
         }
     }
 }
 
 #[async_std::main]
 async fn main() {
-    // Initialize logger with default log level
-    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
-        .init();
+    let args = Args::from_args();
+
+    // Initialize logger with default log level, unless it was given on the
+    // command line
+    let mut logger =
+        env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+    if let Some(log_level) = &args.log_level {
+        logger.parse_filters(log_level);
+    }
+    logger.init();
+
+    let sources = args.config_sources();
+    let exit_code = match args.command {
+        None | Some(Command::Run) => None,
+        Some(Command::CheckConfig) => Some(cli::check_config(&sources)),
+        Some(Command::PrintConfig) => Some(cli::print_config(&sources)),
+        Some(Command::MigrateConfig) => Some(cli::migrate_config(&sources)),
+        Some(Command::PrintSchema) => Some(cli::print_schema()),
+        Some(Command::MotorTest {
+            motor,
+            speed,
+            duration_ms,
+        }) => Some(cli::motor_test(
+            &sources,
+            motor,
+            speed,
+            Duration::from_millis(duration_ms),
+        )),
+        Some(Command::ListGamepads) => Some(cli::list_gamepads(&sources)),
+        Some(Command::I2cScan) => Some(cli::i2c_scan(&sources)),
+    };
+    if let Some(exit_code) = exit_code {
+        process::exit(exit_code);
+    }
 
     log::info!("Initializing robot...");
-    // Read config path from CLI args
-    let config_path = env::args()
-        .nth(1)
-        .unwrap_or_else(|| DEFAULT_CONFIG_PATH.into());
-    let config = RobotConfig::load(&config_path).expect("Error loading config");
+    let config = RobotConfig::load(&sources).expect("Error loading config");
     log::info!("Loaded config:\n{:#?}", config);
+    config.validate().check().expect("Error validating config");
 
-    let robot = Robot::new(config).expect("Error initializing hardware");
+    let robot =
+        Robot::new(sources, config).expect("Error initializing hardware");
     log::info!("Finished initialization");
     robot.run().await;
 }
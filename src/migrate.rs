@@ -0,0 +1,254 @@
+use crate::persist;
+use anyhow::{bail, Context};
+use config::{Config, File};
+use log::{info, warn};
+use serde_json::Value;
+
+/// The current config format version. Whenever a change to the config types
+/// would break existing config files (renaming or moving a field, adding a
+/// required field, etc.), bump this and add a migration to [MIGRATIONS].
+pub const CONFIG_VERSION: u64 = 1;
+
+/// Upgrades a config from one version to the next
+struct Migration {
+    /// The version this upgrades from. It upgrades to `from + 1`.
+    from: u64,
+    /// What changed, for the logs
+    description: &'static str,
+    /// Rewrite the config in place. Override files usually only contain part
+    /// of the config, so this has to handle any key being missing. Profiles
+    /// hold partial `input` and `drive` tables too, which may need the same
+    /// changes.
+    migrate: fn(&mut Value) -> anyhow::Result<()>,
+}
+
+/// Every migration, in order. Each one picks up where the last one left off,
+/// so a config from any old version can be brought up to date.
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: 0,
+    description: "Add config version. The format is otherwise unchanged.",
+    migrate: |_| Ok(()),
+}];
+
+/// Upgrade a config to [CONFIG_VERSION], logging each step. A config without a
+/// `version` field is from before versioning, i.e. version 0. `name` is where
+/// the config came from, for the logs. Returns true if anything was migrated.
+pub fn migrate(name: &str, config: &mut Value) -> anyhow::Result<bool> {
+    migrate_from(name, config, 0, true)
+}
+
+/// Upgrade a config to [CONFIG_VERSION], starting from its `version`, or
+/// `default_version` if it doesn't have one. The new version is only written
+/// into the config if it had one already, or `stamp` is set.
+fn migrate_from(
+    name: &str,
+    config: &mut Value,
+    default_version: u64,
+    stamp: bool,
+) -> anyhow::Result<bool> {
+    let version = match config.get("version") {
+        None => default_version,
+        Some(version) => version.as_u64().with_context(|| {
+            format!("{}: version must be a non-negative integer", name)
+        })?,
+    };
+    if version > CONFIG_VERSION {
+        bail!(
+            "{} is config version {}, but the newest version this robot \
+            understands is {}",
+            name,
+            version,
+            CONFIG_VERSION
+        );
+    }
+    if version == CONFIG_VERSION {
+        return Ok(false);
+    }
+
+    warn!(
+        "{} is config version {}, migrating it to version {}. Run \
+        `robot migrate-config` to update the file.",
+        name, version, CONFIG_VERSION
+    );
+    for migration in MIGRATIONS.iter().filter(|m| m.from >= version) {
+        info!(
+            "Migrating {} from version {} to {}: {}",
+            name,
+            migration.from,
+            migration.from + 1,
+            migration.description
+        );
+        (migration.migrate)(config).with_context(|| {
+            format!(
+                "Migrating {} from version {} to {}",
+                name,
+                migration.from,
+                migration.from + 1
+            )
+        })?;
+    }
+    match config {
+        Value::Object(map) => {
+            if stamp || map.contains_key("version") {
+                map.insert("version".into(), CONFIG_VERSION.into());
+            }
+        }
+        _ => bail!("{}: expected a table", name),
+    }
+    Ok(true)
+}
+
+/// Read a config file as JSON
+fn read_file(path: &str) -> anyhow::Result<Value> {
+    let mut file = Config::new();
+    file.merge(File::with_name(path))
+        .with_context(|| format!("Reading config file {}", path))?;
+    Ok(file.try_into()?)
+}
+
+/// Get the version of a base config file, as written in the file. Override
+/// files without a version of their own are at this version too.
+pub fn file_version(path: &str) -> anyhow::Result<u64> {
+    match read_file(path)?.get("version") {
+        None => Ok(0),
+        Some(version) => version.as_u64().with_context(|| {
+            format!("{}: version must be a non-negative integer", path)
+        }),
+    }
+}
+
+/// Load a config file, migrating it to the current version if needed. For
+/// override files, `base_version` is the [file_version] of the base file.
+/// Only the base file needs a version, so override files without one are
+/// migrated from the base file's version.
+pub fn load_file(
+    path: &str,
+    base_version: Option<u64>,
+) -> anyhow::Result<Config> {
+    let mut file = Config::new();
+    file.merge(File::with_name(path))
+        .with_context(|| format!("Reading config file {}", path))?;
+    let mut value: Value = file.clone().try_into()?;
+    if migrate_from(
+        path,
+        &mut value,
+        base_version.unwrap_or_default(),
+        base_version.is_none(),
+    )? {
+        file = Config::try_from(&value)?;
+    }
+    Ok(file)
+}
+
+/// Rewrite a config file in the current format, if it's outdated. Comments
+/// and formatting are kept where possible, and the old file is backed up
+/// first. `base_version` works like in [load_file], so a version is only
+/// added to the base file. Returns true if the file was rewritten.
+pub fn migrate_file(
+    path: &str,
+    base_version: Option<u64>,
+    backup_dir: &str,
+    max_backups: usize,
+) -> anyhow::Result<bool> {
+    let old = read_file(path)?;
+    let mut new = old.clone();
+    let migrated = migrate_from(
+        path,
+        &mut new,
+        base_version.unwrap_or_default(),
+        base_version.is_none(),
+    )?;
+    // Migrations don't always change anything in an override file
+    if !migrated || new == old {
+        return Ok(false);
+    }
+    persist::write_changes(path, &old, &new, backup_dir, max_backups)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::{fs, path::PathBuf};
+
+    /// Make an empty directory for a test to write files in
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "robot-migrate-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Configs from before versioning are version 0
+    #[test]
+    fn test_unversioned() {
+        let mut config = json!({"drive": {"simulate": true}});
+        assert!(migrate("test", &mut config).unwrap());
+        assert_eq!(
+            config,
+            json!({"version": CONFIG_VERSION, "drive": {"simulate": true}})
+        );
+    }
+
+    #[test]
+    fn test_current() {
+        let mut config = json!({"version": CONFIG_VERSION});
+        assert!(!migrate("test", &mut config).unwrap());
+        assert_eq!(config, json!({"version": CONFIG_VERSION}));
+    }
+
+    #[test]
+    fn test_invalid_version() {
+        for version in [json!(CONFIG_VERSION + 1), json!(-1), json!("1")] {
+            let mut config = json!({ "version": version });
+            assert!(migrate("test", &mut config).is_err(), "{}", version);
+        }
+    }
+
+    /// Override files without a version take the base file's version, and
+    /// don't get one added
+    #[test]
+    fn test_default_version() {
+        let mut config = json!({"drive": {"simulate": true}});
+        assert!(
+            !migrate_from("test", &mut config, CONFIG_VERSION, false).unwrap()
+        );
+        assert!(migrate_from("test", &mut config, 0, false).unwrap());
+        assert_eq!(config, json!({"drive": {"simulate": true}}));
+    }
+
+    /// Only the base file is stamped with a version when it's rewritten
+    #[test]
+    fn test_migrate_file() {
+        let dir = temp_dir("file");
+        let backup_dir = dir.join("backups");
+        let backup_dir = backup_dir.to_str().unwrap();
+        let base = dir.join("base.toml");
+        let base = base.to_str().unwrap();
+        let local = dir.join("local.toml");
+        let local = local.to_str().unwrap();
+        fs::write(base, "# Base\n[drive]\nsimulate = true\n").unwrap();
+        fs::write(local, "[drive]\nsimulate = false\n").unwrap();
+
+        let base_version = file_version(base).unwrap();
+        assert_eq!(base_version, 0);
+        assert!(migrate_file(base, None, backup_dir, 5).unwrap());
+        assert!(
+            !migrate_file(local, Some(base_version), backup_dir, 5).unwrap()
+        );
+        assert_eq!(file_version(base).unwrap(), CONFIG_VERSION);
+        assert!(fs::read_to_string(base).unwrap().contains("# Base"));
+        assert_eq!(
+            fs::read_to_string(local).unwrap(),
+            "[drive]\nsimulate = false\n"
+        );
+        assert!(!migrate_file(base, None, backup_dir, 5).unwrap());
+        assert_eq!(fs::read_dir(backup_dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
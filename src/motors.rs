@@ -1,7 +1,8 @@
 use anyhow::Context;
 use linux_embedded_hal::{i2cdev::linux::LinuxI2CError, I2cdev};
-use log::trace;
+use log::{info, trace};
 use pwm_pca9685::{Channel, Pca9685};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -11,6 +12,32 @@ use crate::config::RobotConfig;
 /// pulse. Based on 12-bit resolution (4096 steps).
 const MAX_DUTY_CYCLE: f32 = 4095.0;
 
+/// Something that can drive motors. Implemented by the real motor HAT, and by
+/// a simulated controller for running without hardware.
+pub trait MotorController {
+    /// Set speed for a motor, -1 to 1
+    fn set_speed(
+        &mut self,
+        channel: MotorChannel,
+        speed: f32,
+    ) -> anyhow::Result<()>;
+}
+
+/// Initialize the drive motor controller. This is the real motor HAT, unless
+/// the config asks for simulated motors.
+pub fn init_drive_motors(
+    config: &RobotConfig,
+) -> anyhow::Result<Box<dyn MotorController>> {
+    if config.drive.simulate {
+        info!("Using simulated drive motors");
+        Ok(Box::new(SimulatedMotors::default()))
+    } else {
+        Ok(Box::new(
+            MotorHat::new(config).context("Initializing drive motors")?,
+        ))
+    }
+}
+
 /// Controller for Adafruit's Motor HAT board. Controls up to 4 DC motors with
 /// PWM.
 ///
@@ -52,16 +79,6 @@ impl MotorHat {
         Ok(Self { pwm, motors })
     }
 
-    /// Set speed for a motor, -1 to 1
-    pub fn set_speed(
-        &mut self,
-        channel: MotorChannel,
-        speed: f32,
-    ) -> anyhow::Result<()> {
-        let motor = self.motors.get(&channel).unwrap(); // TODO no unwrap
-        motor.set_speed(&mut self.pwm, speed)
-    }
-
     /// Turn all motors off. Called automatically on drop.
     pub fn off(&mut self) -> anyhow::Result<()> {
         for motor in self.motors.values() {
@@ -74,6 +91,17 @@ impl MotorHat {
     }
 }
 
+impl MotorController for MotorHat {
+    fn set_speed(
+        &mut self,
+        channel: MotorChannel,
+        speed: f32,
+    ) -> anyhow::Result<()> {
+        let motor = self.motors.get(&channel).unwrap(); // TODO no unwrap
+        motor.set_speed(&mut self.pwm, speed)
+    }
+}
+
 // Turn off all motors on drop
 impl Drop for MotorHat {
     fn drop(&mut self) {
@@ -85,6 +113,40 @@ impl Drop for MotorHat {
     }
 }
 
+/// A fake motor controller, which just logs motor speeds whenever they
+/// change. Useful for testing input without any hardware, e.g. when replaying
+/// an input recording.
+#[derive(Debug, Default)]
+pub struct SimulatedMotors {
+    speeds: HashMap<MotorChannel, f32>,
+}
+
+#[cfg(test)]
+impl SimulatedMotors {
+    /// Get the last speed a motor was set to
+    pub fn speed(&self, channel: MotorChannel) -> f32 {
+        self.speeds.get(&channel).copied().unwrap_or(0.0)
+    }
+}
+
+impl MotorController for SimulatedMotors {
+    fn set_speed(
+        &mut self,
+        channel: MotorChannel,
+        speed: f32,
+    ) -> anyhow::Result<()> {
+        // Same validation as the real motors
+        anyhow::ensure!(
+            (-1.0..=1.0).contains(&speed),
+            "Speed must be in range [-1, 1]"
+        );
+        if self.speeds.insert(channel, speed) != Some(speed) {
+            info!("Simulated motor {:?} speed: {}", channel, speed);
+        }
+        Ok(())
+    }
+}
+
 /// The 3 PWM channels for a single motor on the motor HAT\
 struct MotorHatChannels {
     ref_channel: Channel,
@@ -94,7 +156,9 @@ struct MotorHatChannels {
 
 /// A reference to a single Motor on the HAT. These numbers line up with the
 /// numbers printed on the HAT PCB.
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema,
+)]
 #[serde(rename_all = "snake_case")]
 pub enum MotorChannel {
     Motor1,
@@ -0,0 +1,529 @@
+use crate::{
+    config::{ConfigSources, RobotConfig},
+    migrate::CONFIG_VERSION,
+};
+use anyhow::{bail, Context};
+use config::{Config, File};
+use log::{debug, info};
+use serde_json::Value as JsonValue;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use toml_edit::{Array, Document, InlineTable, Item, Value};
+
+/// Config changes that can't be persisted, because their values come from a
+/// source other than the base config file. Writing them to the base file
+/// wouldn't do anything, since the other source would still win.
+#[derive(Debug)]
+pub struct OverriddenChanges {
+    /// Each overridden key, with the source that sets it
+    pub keys: Vec<(String, String)>,
+}
+
+impl fmt::Display for OverriddenChanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keys: Vec<String> = self
+            .keys
+            .iter()
+            .map(|(key, source)| format!("{} (set by {})", key, source))
+            .collect();
+        write!(
+            f,
+            "Can't save changes to values from other config sources: {}",
+            keys.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for OverriddenChanges {}
+
+/// Where config changes are persisted: the base config file, along with
+/// which values other sources (override files, environment variables, etc.)
+/// set. Reading this means reading every source, so it's done before the
+/// config is locked.
+#[derive(Debug)]
+pub struct PersistTarget {
+    path: String,
+    /// The source of each value, by dotted key. See
+    /// [ConfigSources::key_sources].
+    key_sources: HashMap<String, String>,
+}
+
+impl PersistTarget {
+    /// Read the config sources. Fails if the base file is outdated, since
+    /// writing current-format values into it would corrupt it.
+    pub fn load(sources: &ConfigSources) -> anyhow::Result<Self> {
+        let path = sources.path.as_str();
+        let mut file = Config::new();
+        file.merge(File::with_name(path))
+            .with_context(|| format!("Reading config file {}", path))?;
+        let version = file.get::<u64>("version").unwrap_or_default();
+        if version != CONFIG_VERSION {
+            bail!(
+                "{} is config version {}, but changes are in version {}. Run \
+                `robot migrate-config` to update the file.",
+                path,
+                version,
+                CONFIG_VERSION
+            );
+        }
+        Ok(Self {
+            path: path.into(),
+            key_sources: sources.key_sources()?,
+        })
+    }
+
+    /// Work out how to write a config change back to the base config file.
+    /// If any changed value comes from another source, it can't be, and an
+    /// [OverriddenChanges] error is returned.
+    pub fn prepare(
+        &self,
+        old: &RobotConfig,
+        new: &RobotConfig,
+    ) -> anyhow::Result<PendingWrite> {
+        let old_value = serde_json::to_value(old)?;
+        let new_value = serde_json::to_value(new)?;
+
+        // A value can be set by a source as a whole, or just in part
+        let mut overridden = Vec::new();
+        for change in diff(&old_value, &new_value) {
+            let key = change.key.join(".");
+            overridden.extend(
+                self.key_sources
+                    .iter()
+                    .filter(|(source_key, source)| {
+                        **source != self.path
+                            && (**source_key == key
+                                || source_key.starts_with(&format!("{}.", key))
+                                || key.starts_with(&format!("{}.", source_key)))
+                    })
+                    .map(|(key, source)| (key.clone(), source.clone())),
+            );
+        }
+        if !overridden.is_empty() {
+            overridden.sort();
+            overridden.dedup();
+            return Err(OverriddenChanges { keys: overridden }.into());
+        }
+
+        Ok(PendingWrite {
+            path: self.path.clone(),
+            old: old_value,
+            new: new_value,
+            backup_dir: old.api.backup_dir.clone(),
+            max_backups: old.api.max_backups,
+        })
+    }
+}
+
+/// A config change that's ready to be written back to the base config file,
+/// so it survives a restart. See [PendingWrite::write].
+#[derive(Debug)]
+pub struct PendingWrite {
+    path: String,
+    old: JsonValue,
+    new: JsonValue,
+    backup_dir: String,
+    max_backups: usize,
+}
+
+impl PendingWrite {
+    /// Write the change. Only the values that differ are written, so
+    /// comments and formatting are left alone. The current file is backed
+    /// up first, and the new one is written atomically, so a crash can't
+    /// leave a half-written config behind.
+    pub fn write(&self) -> anyhow::Result<()> {
+        write_changes(
+            &self.path,
+            &self.old,
+            &self.new,
+            &self.backup_dir,
+            self.max_backups,
+        )
+    }
+}
+
+/// Write the differences between two versions of a config to the file at
+/// `path`, backing it up first. See [PendingWrite::write].
+pub fn write_changes(
+    path: &str,
+    old: &JsonValue,
+    new: &JsonValue,
+    backup_dir: &str,
+    max_backups: usize,
+) -> anyhow::Result<()> {
+    let path = Path::new(path);
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Reading config file {}", path.display()))?;
+    let mut document: Document = content
+        .parse()
+        .with_context(|| format!("Parsing config file {}", path.display()))?;
+
+    let changes = diff(old, new);
+    if changes.is_empty() {
+        debug!("Config unchanged, not writing {}", path.display());
+        return Ok(());
+    }
+    for Change { key, new, .. } in changes {
+        debug!("Persisting config change: {} = {:?}", key.join("."), new);
+        match new {
+            None => remove_key(&mut document.root, &key),
+            Some(value) => set_key(&mut document.root, &key, value)
+                .with_context(|| format!("Setting {}", key.join(".")))?,
+        }
+    }
+
+    let backup_path = backup(path, backup_dir, max_backups)?;
+    info!("Backed up {} to {}", path.display(), backup_path.display());
+    write_atomic(path, &document.to_string())?;
+    info!("Saved config changes to {}", path.display());
+    Ok(())
+}
+
+/// One value that differs between two configs
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    /// Path to the value, one key per table
+    pub key: Vec<String>,
+    /// The old value, or None if it wasn't set
+    pub old: Option<JsonValue>,
+    /// The new value, or None if it was removed
+    pub new: Option<JsonValue>,
+}
+
+/// Find every value that differs between two configs. Tables are compared
+/// key by key, and anything else (including arrays) is compared as a whole.
+pub fn diff(old: &JsonValue, new: &JsonValue) -> Vec<Change> {
+    let mut changes = Vec::new();
+    diff_values(&mut Vec::new(), Some(old), Some(new), &mut changes);
+    changes
+}
+
+fn diff_values(
+    key: &mut Vec<String>,
+    old: Option<&JsonValue>,
+    new: Option<&JsonValue>,
+    changes: &mut Vec<Change>,
+) {
+    // A null is just a missing optional value
+    let old = old.filter(|value| !value.is_null());
+    let new = new.filter(|value| !value.is_null());
+    match (old, new) {
+        (Some(JsonValue::Object(old)), Some(JsonValue::Object(new))) => {
+            let keys: BTreeSet<&String> =
+                old.keys().chain(new.keys()).collect();
+            for k in keys {
+                key.push(k.clone());
+                diff_values(key, old.get(k), new.get(k), changes);
+                key.pop();
+            }
+        }
+        (old, new) if old == new => {}
+        (old, new) => changes.push(Change {
+            key: key.clone(),
+            old: old.cloned(),
+            new: new.cloned(),
+        }),
+    }
+}
+
+/// Set a value in a TOML document, creating any missing tables along the way
+/// (as inline tables)
+fn set_key(
+    root: &mut Item,
+    key: &[String],
+    value: JsonValue,
+) -> anyhow::Result<()> {
+    let (last, parents) = key.split_last().context("Empty key")?;
+    let mut item = root;
+    for k in parents {
+        if item.is_none() {
+            *item = toml_edit::value(InlineTable::default());
+        }
+        if !item.is_table_like() {
+            bail!("{} is not a table", k);
+        }
+        item = &mut item[k.as_str()];
+    }
+    if item.is_none() {
+        *item = toml_edit::value(InlineTable::default());
+    }
+    if !item.is_table_like() {
+        bail!("Parent of {} is not a table", last);
+    }
+    let value = to_toml(value)?;
+    // Keep the whitespace and any comment around the old value
+    item[last.as_str()] = match item[last.as_str()].as_value() {
+        Some(old) => {
+            let decor = old.decor();
+            Item::Value(toml_edit::decorated(
+                value,
+                decor.prefix(),
+                decor.suffix(),
+            ))
+        }
+        None => toml_edit::value(value),
+    };
+    Ok(())
+}
+
+/// Remove a value from a TOML document, if it's there
+fn remove_key(root: &mut Item, key: &[String]) {
+    let (last, parents) = match key.split_last() {
+        Some(split) => split,
+        None => return,
+    };
+    let mut item = root;
+    for k in parents {
+        // Indexing immutably doesn't insert anything
+        if item[k.as_str()].is_none() {
+            return;
+        }
+        item = &mut item[k.as_str()];
+    }
+    if let Some(table) = item.as_table_mut() {
+        table.remove(last);
+    } else if let Some(table) = item.as_inline_table_mut() {
+        table.remove(last);
+    }
+}
+
+/// Convert a JSON value to a TOML value
+fn to_toml(value: JsonValue) -> anyhow::Result<Value> {
+    Ok(match value {
+        JsonValue::Null => bail!("TOML can't represent null"),
+        JsonValue::Bool(b) => b.into(),
+        JsonValue::Number(n) => match n.as_i64() {
+            Some(i) => i.into(),
+            // All floats in the config are f32, so format them as f32 to
+            // avoid writing out noise like 0.30000001192092896. Debug
+            // formatting always includes a decimal point, so whole numbers
+            // stay floats.
+            None => {
+                let f = n.as_f64().unwrap_or_default() as f32;
+                format!("{:?}", f)
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Can't write {} to TOML", f))?
+            }
+        },
+        JsonValue::String(s) => s.as_str().into(),
+        JsonValue::Array(values) => {
+            let mut array = Array::default();
+            for value in values {
+                if array.push(to_toml(value)?).is_err() {
+                    bail!("TOML arrays can't mix types");
+                }
+            }
+            array.into()
+        }
+        JsonValue::Object(map) => {
+            let mut table = InlineTable::default();
+            for (k, value) in map {
+                // Nulls are just missing optional values
+                if !value.is_null() {
+                    table.get_or_insert(&k, to_toml(value)?);
+                }
+            }
+            table.into()
+        }
+    })
+}
+
+/// Copy a file into the backup directory, with a timestamp in its name, and
+/// delete the oldest backups beyond `max_backups`. Returns the backup path.
+fn backup(
+    path: &Path,
+    backup_dir: &str,
+    max_backups: usize,
+) -> anyhow::Result<PathBuf> {
+    let backup_dir = Path::new(backup_dir);
+    fs::create_dir_all(backup_dir).with_context(|| {
+        format!("Creating backup directory {}", backup_dir.display())
+    })?;
+
+    // Timestamps sort chronologically, so the oldest backup sorts first.
+    // They're precise enough that several writes in a row don't overwrite
+    // each other's backups, and just in case, we wait for a new timestamp.
+    // Colons aren't allowed in file names everywhere.
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("config");
+    let backup_path = loop {
+        let timestamp = humantime::format_rfc3339_nanos(SystemTime::now())
+            .to_string()
+            .replace(':', "-");
+        let backup_path =
+            backup_dir.join(format!("{}.{}.toml", stem, timestamp));
+        if !backup_path.exists() {
+            break backup_path;
+        }
+    };
+    fs::copy(path, &backup_path).with_context(|| {
+        format!("Backing up {} to {}", path.display(), backup_path.display())
+    })?;
+
+    let prefix = format!("{}.", stem);
+    let mut backups: Vec<PathBuf> = fs::read_dir(backup_dir)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&prefix))
+        })
+        .collect();
+    backups.sort();
+    // Never delete the backup we just made
+    backups.retain(|old_backup| *old_backup != backup_path);
+    let excess = backups.len().saturating_sub(max_backups.saturating_sub(1));
+    for old_backup in &backups[..excess] {
+        debug!("Deleting old config backup {}", old_backup.display());
+        fs::remove_file(old_backup)?;
+    }
+
+    Ok(backup_path)
+}
+
+/// Write a file atomically, by writing to a temporary file next to it and
+/// renaming that over the original
+pub fn write_atomic(path: &Path, content: &str) -> anyhow::Result<()> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("Invalid config path")?;
+    let temp_path = path.with_file_name(format!(".{}.tmp", file_name));
+
+    let mut file = fs::File::create(&temp_path)
+        .with_context(|| format!("Creating {}", temp_path.display()))?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path).with_context(|| {
+        format!("Renaming {} to {}", temp_path.display(), path.display())
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Make an empty directory for a test to write files in
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "robot-persist-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Get the file names in a directory, sorted
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = json!({
+            "a": {"b": 1, "c": [1, 2], "d": null},
+            "e": "same",
+        });
+        let new = json!({
+            "a": {"b": 2, "c": [1, 2, 3], "d": true},
+            "e": "same",
+            "f": {"g": 1},
+        });
+        let keys: Vec<String> = diff(&old, &new)
+            .iter()
+            .map(|change| change.key.join("."))
+            .collect();
+        assert_eq!(keys, ["a.b", "a.c", "a.d", "f"]);
+        assert!(diff(&new, &new).is_empty());
+        // Nulls are the same as missing values
+        assert!(diff(&json!({"a": null}), &json!({})).is_empty());
+    }
+
+    /// Only changed values are rewritten, so comments survive
+    #[test]
+    fn test_write_changes() {
+        let dir = temp_dir("write");
+        let path = dir.join("robot.toml");
+        fs::write(
+            &path,
+            "# The robot\n[drive]\nsimulate = false # Real motors\n\
+            i2c_address = 96\n",
+        )
+        .unwrap();
+        let old = json!({"drive": {"simulate": false, "i2c_address": 96}});
+        let new = json!({
+            "drive": {"simulate": true},
+            "api": {"host": "127.0.0.1:8000"},
+        });
+        let backup_dir = dir.join("backups");
+        write_changes(
+            path.to_str().unwrap(),
+            &old,
+            &new,
+            backup_dir.to_str().unwrap(),
+            5,
+        )
+        .unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains("# The robot"), "{}", content);
+        assert!(
+            content.contains("simulate = true # Real motors"),
+            "{}",
+            content
+        );
+        assert!(!content.contains("i2c_address"), "{}", content);
+        assert!(content.contains("127.0.0.1:8000"), "{}", content);
+        let document: Document = content.parse().unwrap();
+        assert_eq!(document["api"]["host"].as_str(), Some("127.0.0.1:8000"));
+        assert_eq!(file_names(&backup_dir).len(), 1);
+        assert_eq!(file_names(&dir), ["backups", "robot.toml"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Only the newest backups are kept, including the one just made
+    #[test]
+    fn test_backup_pruning() {
+        let dir = temp_dir("backup");
+        let path = dir.join("robot.toml");
+        let backup_dir = dir.join("backups");
+        let backup_dir_str = backup_dir.to_str().unwrap();
+        // Backups of other files don't count
+        fs::create_dir_all(&backup_dir).unwrap();
+        fs::write(backup_dir.join("other.toml"), "").unwrap();
+
+        let mut made = Vec::new();
+        for i in 0..5 {
+            fs::write(&path, format!("version = {}", i)).unwrap();
+            made.push(backup(&path, backup_dir_str, 3).unwrap());
+        }
+        let mut expected = vec!["other.toml".to_owned()];
+        expected.extend(made[2..].iter().map(|path| {
+            path.file_name().unwrap().to_str().unwrap().to_owned()
+        }));
+        assert_eq!(file_names(&backup_dir), expected);
+        assert_eq!(fs::read_to_string(&made[4]).unwrap(), "version = 4");
+
+        // With a single backup, the new one replaces the rest
+        let newest = backup(&path, backup_dir_str, 1).unwrap();
+        assert!(newest.exists());
+        assert_eq!(file_names(&backup_dir).len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+use crate::{config::RobotConfig, input::InputHandler};
+use anyhow::Context;
+use log::info;
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+
+/// A named set of overrides for the input and drive config, e.g. slow speeds
+/// for a "demo" profile. Each section is merged over the base config like a
+/// JSON merge patch: tables are merged key by key, and any other value
+/// replaces the base value.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct Profile {
+    /// Human-readable description, shown in the API
+    pub description: String,
+    /// Overrides for [RobotConfig::input]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<Value>,
+    /// Overrides for [RobotConfig::drive]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drive: Option<Value>,
+}
+
+/// Apply a JSON merge patch ([RFC 7396](https://tools.ietf.org/html/rfc7396))
+/// to a value. Objects in the patch are merged into the target key by key, a
+/// null removes the key, and anything else replaces the target.
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Default::default());
+            }
+            if let Value::Object(target) = target {
+                for (key, value) in patch {
+                    if value.is_null() {
+                        target.remove(key);
+                    } else {
+                        merge_patch(
+                            target.entry(key.as_str()).or_insert(Value::Null),
+                            value,
+                        );
+                    }
+                }
+            }
+        }
+        _ => *target = patch.clone(),
+    }
+}
+
+/// Merge a patch into a config section, by way of JSON
+fn apply_patch<T: Serialize + DeserializeOwned>(
+    base: &T,
+    patch: &Value,
+) -> anyhow::Result<T> {
+    let mut value = serde_json::to_value(base)?;
+    merge_patch(&mut value, patch);
+    Ok(serde_json::from_value(value)?)
+}
+
+impl RobotConfig {
+    /// Get a copy of this config with a profile's overrides applied
+    pub fn with_profile(&self, name: &str) -> anyhow::Result<Self> {
+        let profile = self
+            .profiles
+            .get(name)
+            .with_context(|| format!("No profile named {:?}", name))?;
+        let mut config = self.clone();
+        if let Some(patch) = &profile.input {
+            config.input = apply_patch(&self.input, patch)
+                .with_context(|| format!("Applying profile {:?}", name))?;
+        }
+        if let Some(patch) = &profile.drive {
+            config.drive = apply_patch(&self.drive, patch)
+                .with_context(|| format!("Applying profile {:?}", name))?;
+        }
+        Ok(config)
+    }
+
+    /// Get the config with the active profile applied. If no profile is
+    /// active, this is just the config itself.
+    pub fn resolve_profile(&self) -> anyhow::Result<Cow<'_, Self>> {
+        match &self.profile {
+            None => Ok(Cow::Borrowed(self)),
+            Some(name) => Ok(Cow::Owned(self.with_profile(name)?)),
+        }
+    }
+}
+
+/// Switches between profiles with a gamepad button. Each press moves to the
+/// next profile (in alphabetical order), and after the last one, back to no
+/// profile. Should be updated once per loop.
+#[derive(Debug, Default)]
+pub struct ProfileSwitcher {
+    /// Was the button held on the last update, so each press only triggers
+    /// once
+    held: bool,
+}
+
+impl ProfileSwitcher {
+    /// Check the profile button on the controlling gamepad. If it was just
+    /// pressed, returns the profile to switch to (None meaning no profile).
+    pub fn update(
+        &mut self,
+        config: &RobotConfig,
+        input_handler: &InputHandler,
+    ) -> Option<Option<String>> {
+        let held = match (
+            config.input.profile_button,
+            input_handler.controlling_slot(),
+        ) {
+            (Some(button), Some(gamepad)) => {
+                input_handler.is_button_pressed(gamepad, button)
+            }
+            _ => false,
+        };
+        let pressed = held && !self.held;
+        self.held = held;
+        if !pressed || config.profiles.is_empty() {
+            return None;
+        }
+
+        let mut names = config.profiles.keys();
+        let next = match &config.profile {
+            None => names.next(),
+            Some(current) => names.skip_while(|name| *name != current).nth(1),
+        };
+        info!("Switching to profile {:?}", next);
+        Some(next.cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Examples from RFC 7396, appendix A
+    #[test]
+    fn test_merge_patch() {
+        for (target, patch, expected) in [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (
+                json!({"a": "b"}),
+                json!({"b": "c"}),
+                json!({"a": "b", "b": "c"}),
+            ),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (
+                json!({"a": "b", "b": "c"}),
+                json!({"a": null}),
+                json!({"b": "c"}),
+            ),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (
+                json!({"a": [{"b": "c"}]}),
+                json!({"a": [1]}),
+                json!({"a": [1]}),
+            ),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (
+                json!({"e": null}),
+                json!({"a": 1}),
+                json!({"e": null, "a": 1}),
+            ),
+            (
+                json!([1, 2]),
+                json!({"a": "b", "c": null}),
+                json!({"a": "b"}),
+            ),
+            (
+                json!({}),
+                json!({"a": {"bb": {"ccc": null}}}),
+                json!({"a": {"bb": {}}}),
+            ),
+        ] {
+            let mut value = target.clone();
+            merge_patch(&mut value, &patch);
+            assert_eq!(value, expected, "{} + {}", target, patch);
+        }
+    }
+
+    #[test]
+    fn test_with_profile() {
+        let config = RobotConfig::test_default();
+        let competition = config.with_profile("competition").unwrap();
+        assert_eq!(competition.input.speed.gears, [0.6, 1.0]);
+        // Everything else is left alone
+        assert_eq!(
+            competition.input.speed.turbo_speed,
+            config.input.speed.turbo_speed
+        );
+        assert_eq!(competition.drive.i2c_address, config.drive.i2c_address);
+        assert!(config.with_profile("nope").is_err());
+
+        // Overrides have to deserialize
+        let mut config = config;
+        config.profiles.insert(
+            "broken".into(),
+            Profile {
+                drive: Some(json!({"i2c_address": "nope"})),
+                ..Default::default()
+            },
+        );
+        assert!(config.with_profile("broken").is_err());
+    }
+
+    #[test]
+    fn test_resolve_profile() {
+        let mut config = RobotConfig::test_default();
+        assert!(matches!(
+            config.resolve_profile().unwrap(),
+            Cow::Borrowed(_)
+        ));
+        config.profile = Some("competition".into());
+        assert_eq!(
+            config.resolve_profile().unwrap().input.speed.gears,
+            [0.6, 1.0]
+        );
+    }
+}
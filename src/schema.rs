@@ -0,0 +1,96 @@
+use crate::{
+    config::RobotConfig,
+    input::{TriggerSource, AXES, BUTTONS},
+};
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{
+        InstanceType, ObjectValidation, RootSchema, Schema, SchemaObject,
+        SubschemaValidation,
+    },
+    schema_for, JsonSchema,
+};
+use serde::Serialize;
+
+/// Generate a JSON Schema for the robot config, for editors and UIs to
+/// validate and autocomplete against. This only covers the shape of the
+/// config; see [RobotConfig::validate] for everything else.
+pub fn config_schema() -> RootSchema {
+    schema_for!(RobotConfig)
+}
+
+/// Stand-in for [gilrs::Axis] in the schema, since gilrs doesn't implement
+/// [JsonSchema]. Use it with `#[schemars(with = "AxisName")]`.
+pub struct AxisName;
+
+impl JsonSchema for AxisName {
+    fn schema_name() -> String {
+        "Axis".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        string_enum(AXES)
+    }
+}
+
+/// Stand-in for [gilrs::Button] in the schema. See [AxisName].
+pub struct ButtonName;
+
+impl JsonSchema for ButtonName {
+    fn schema_name() -> String {
+        "Button".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        string_enum(BUTTONS)
+    }
+}
+
+/// This is flattened into [InputTrigger](crate::input::InputTrigger), next to
+/// other fields. The derived schema would forbid any fields besides the
+/// variant's, so it's written by hand.
+impl JsonSchema for TriggerSource {
+    fn schema_name() -> String {
+        "TriggerSource".into()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let variant = |name: &str, schema: Schema| -> Schema {
+            let mut object = ObjectValidation::default();
+            object.required.insert(name.into());
+            object.properties.insert(name.into(), schema);
+            SchemaObject {
+                instance_type: Some(InstanceType::Object.into()),
+                object: Some(Box::new(object)),
+                ..Default::default()
+            }
+            .into()
+        };
+        SchemaObject {
+            subschemas: Some(Box::new(SubschemaValidation {
+                one_of: Some(vec![
+                    variant("axis", gen.subschema_for::<AxisName>()),
+                    variant("button", gen.subschema_for::<ButtonName>()),
+                ]),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+/// Build a schema that accepts any of the given values, as they serialize
+fn string_enum<T: Serialize>(values: &[T]) -> Schema {
+    SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        enum_values: Some(
+            values
+                .iter()
+                .filter_map(|value| serde_json::to_value(value).ok())
+                .collect(),
+        ),
+        ..Default::default()
+    }
+    .into()
+}
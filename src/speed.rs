@@ -0,0 +1,213 @@
+use crate::{input::InputHandler, schema::ButtonName};
+use gilrs::Button;
+use log::info;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Configuration for drive speed gears. Every motor value is scaled by the
+/// speed of the active gear, after the drive input mapping is applied. The
+/// turbo and precision buttons temporarily override the gear while held.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct SpeedConfig {
+    /// Speed multiplier for each gear, in [0, 1], slowest first
+    pub gears: Vec<f32>,
+    /// Index of the gear to start in
+    pub initial_gear: usize,
+    /// Button on the controlling gamepad that shifts up one gear
+    #[schemars(with = "Option<ButtonName>")]
+    pub gear_up_button: Option<Button>,
+    /// Button on the controlling gamepad that shifts down one gear
+    #[schemars(with = "Option<ButtonName>")]
+    pub gear_down_button: Option<Button>,
+    /// Button on the controlling gamepad that switches to [Self::turbo_speed]
+    /// while held
+    #[schemars(with = "Option<ButtonName>")]
+    pub turbo_button: Option<Button>,
+    pub turbo_speed: f32,
+    /// Button on the controlling gamepad that switches to
+    /// [Self::precision_speed] while held. Takes priority over turbo.
+    #[schemars(with = "Option<ButtonName>")]
+    pub precision_button: Option<Button>,
+    pub precision_speed: f32,
+}
+
+impl Default for SpeedConfig {
+    fn default() -> Self {
+        Self {
+            // Full speed, with no buttons bound, so configs without any
+            // speed settings aren't limited
+            gears: vec![1.0],
+            initial_gear: 0,
+            gear_up_button: None,
+            gear_down_button: None,
+            turbo_button: None,
+            turbo_speed: 1.0,
+            precision_button: None,
+            precision_speed: 0.15,
+        }
+    }
+}
+
+/// A held button that overrides the active gear
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpeedModifier {
+    Turbo,
+    Precision,
+}
+
+/// The current speed state, as reported via the API
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SpeedStatus {
+    /// Index of the active gear
+    pub gear: usize,
+    /// Number of configured gears
+    pub gear_count: usize,
+    /// The held modifier, if any
+    pub modifier: Option<SpeedModifier>,
+    /// The multiplier currently being applied to all motor values
+    pub scale: f32,
+}
+
+/// Tracks the active speed gear, based on button presses on the controlling
+/// gamepad
+#[derive(Debug)]
+pub struct SpeedControl {
+    gear: usize,
+    modifier: Option<SpeedModifier>,
+    /// Buttons that were held on the last update, so we only shift once per
+    /// press
+    held_buttons: HashSet<Button>,
+}
+
+impl SpeedControl {
+    pub fn new(config: &SpeedConfig) -> Self {
+        let mut rv = Self {
+            gear: config.initial_gear,
+            modifier: None,
+            held_buttons: HashSet::new(),
+        };
+        rv.gear = rv.gear(config);
+        rv
+    }
+
+    /// Read the speed buttons from the device in the given gamepad slot (if
+    /// any), and shift gears accordingly. Should be called once per loop, after
+    /// the input handler has been updated.
+    pub fn update(
+        &mut self,
+        config: &SpeedConfig,
+        input_handler: &InputHandler,
+        gamepad: Option<&str>,
+    ) {
+        let held_buttons: HashSet<Button> = [
+            config.gear_up_button,
+            config.gear_down_button,
+            config.turbo_button,
+            config.precision_button,
+        ]
+        .iter()
+        .copied()
+        .flatten()
+        .filter(|&button| {
+            gamepad.is_some_and(|gamepad| {
+                input_handler.is_button_pressed(gamepad, button)
+            })
+        })
+        .collect();
+        let is_held = |button: Option<Button>| {
+            button.is_some_and(|button| held_buttons.contains(&button))
+        };
+        let is_pressed = |button: Option<Button>| {
+            is_held(button)
+                && button
+                    .is_some_and(|button| !self.held_buttons.contains(&button))
+        };
+
+        // The gear list may have changed since the last update
+        let mut gear = self.gear(config);
+        if is_pressed(config.gear_up_button) && gear + 1 < config.gears.len() {
+            gear += 1;
+        }
+        if is_pressed(config.gear_down_button) && gear > 0 {
+            gear -= 1;
+        }
+        if gear != self.gear {
+            info!(
+                "Shifted to gear {} ({})",
+                gear,
+                config.gears.get(gear).copied().unwrap_or(1.0)
+            );
+        }
+
+        let modifier = if is_held(config.precision_button) {
+            Some(SpeedModifier::Precision)
+        } else if is_held(config.turbo_button) {
+            Some(SpeedModifier::Turbo)
+        } else {
+            None
+        };
+        if modifier != self.modifier {
+            info!("Speed modifier: {:?}", modifier);
+        }
+
+        self.gear = gear;
+        self.modifier = modifier;
+        self.held_buttons = held_buttons;
+    }
+
+    /// Get the index of the active gear, clamped to the configured gears
+    fn gear(&self, config: &SpeedConfig) -> usize {
+        self.gear.min(config.gears.len().saturating_sub(1))
+    }
+
+    /// Get the multiplier that should be applied to every motor value. If no
+    /// gears are configured, speed is not limited.
+    pub fn scale(&self, config: &SpeedConfig) -> f32 {
+        match self.modifier {
+            Some(SpeedModifier::Turbo) => config.turbo_speed,
+            Some(SpeedModifier::Precision) => config.precision_speed,
+            None => config.gears.get(self.gear(config)).copied().unwrap_or(1.0),
+        }
+    }
+
+    /// Get the current speed state, for the API
+    pub fn status(&self, config: &SpeedConfig) -> SpeedStatus {
+        SpeedStatus {
+            gear: self.gear(config),
+            gear_count: config.gears.len(),
+            modifier: self.modifier,
+            scale: self.scale(config),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configs without any speed settings run at full speed
+    #[test]
+    fn test_default_full_speed() {
+        let config = SpeedConfig::default();
+        assert_eq!(SpeedControl::new(&config).scale(&config), 1.0);
+    }
+
+    #[test]
+    fn test_initial_gear() {
+        let config = SpeedConfig {
+            gears: vec![0.3, 0.6, 1.0],
+            initial_gear: 1,
+            ..Default::default()
+        };
+        assert_eq!(SpeedControl::new(&config).scale(&config), 0.6);
+        // Out of range gears are clamped
+        let config = SpeedConfig {
+            initial_gear: 5,
+            ..config
+        };
+        assert_eq!(SpeedControl::new(&config).scale(&config), 1.0);
+    }
+}
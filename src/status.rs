@@ -0,0 +1,21 @@
+use crate::{
+    input::{ConnectionState, ControlStatus},
+    macros::MacroStatus,
+    speed::SpeedStatus,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Live robot state, written by the main loop and readable via the API. Unlike
+/// [RobotConfig](crate::config::RobotConfig), this is never written by users.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RobotStatus {
+    /// Connection state of each gamepad slot in the input config
+    pub gamepads: HashMap<String, ConnectionState>,
+    /// Which gamepad slot is in control of the drive
+    pub control: ControlStatus,
+    /// Active drive speed gear
+    pub speed: SpeedStatus,
+    /// The macro that's currently driving, if any
+    pub running_macro: Option<MacroStatus>,
+}
@@ -0,0 +1,129 @@
+use crate::config::TlsConfig;
+use anyhow::{bail, Context};
+use log::info;
+use rcgen::{
+    Certificate, CertificateParams, DistinguishedName, DnType, SanType,
+};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+/// Make sure the API's certificate and key exist. If neither does and the
+/// config allows it, generate a self-signed pair.
+pub fn ensure_cert(config: &TlsConfig) -> anyhow::Result<()> {
+    let cert_path = Path::new(&config.cert_path);
+    let key_path = Path::new(&config.key_path);
+    match (cert_path.exists(), key_path.exists()) {
+        (true, true) => Ok(()),
+        (false, false) if config.generate_self_signed => {
+            generate_self_signed(config)
+        }
+        (false, false) => bail!(
+            "TLS certificate {} and key {} don't exist. Set \
+            api.tls.generate_self_signed to generate them.",
+            config.cert_path,
+            config.key_path
+        ),
+        // Don't generate over half of an existing pair
+        (false, true) => {
+            bail!("TLS certificate {} doesn't exist", config.cert_path)
+        }
+        (true, false) => bail!("TLS key {} doesn't exist", config.key_path),
+    }
+}
+
+/// Generate a self-signed certificate and key, and write them to the paths
+/// in the config
+fn generate_self_signed(config: &TlsConfig) -> anyhow::Result<()> {
+    info!(
+        "Generating a self-signed TLS certificate for {}",
+        config.self_signed_names.join(", ")
+    );
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "Robot API");
+    params.subject_alt_names = config
+        .self_signed_names
+        .iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(name.clone()),
+        })
+        .collect();
+    let cert = Certificate::from_params(params)
+        .context("Generating TLS certificate")?;
+
+    let cert_pem = cert.serialize_pem()?;
+    let key_pem = cert.serialize_private_key_pem();
+
+    // Write both files to temporary paths first, then move them into place,
+    // so a failure can't leave half a pair behind. Only the robot should be
+    // able to read the key.
+    let cert_temp = write_temp(&config.cert_path, &cert_pem, 0o644)?;
+    let key_temp = match write_temp(&config.key_path, &key_pem, 0o600) {
+        Ok(key_temp) => key_temp,
+        Err(err) => {
+            let _ = fs::remove_file(&cert_temp);
+            return Err(err);
+        }
+    };
+    // The key goes first, so it can be taken back out if the certificate
+    // can't be moved
+    if let Err(err) = rename(&key_temp, &config.key_path) {
+        let _ = fs::remove_file(&cert_temp);
+        let _ = fs::remove_file(&key_temp);
+        return Err(err);
+    }
+    if let Err(err) = rename(&cert_temp, &config.cert_path) {
+        let _ = fs::remove_file(&cert_temp);
+        let _ = fs::remove_file(&config.key_path);
+        return Err(err);
+    }
+
+    info!(
+        "Wrote TLS certificate to {} and key to {}",
+        config.cert_path, config.key_path
+    );
+    Ok(())
+}
+
+/// Write a new PEM file with the given permissions, next to `path` but under
+/// a temporary name, creating its directory if needed. Returns the temporary
+/// path.
+fn write_temp(path: &str, content: &str, mode: u32) -> anyhow::Result<PathBuf> {
+    let path = Path::new(path);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Creating directory {}", dir.display()))?;
+    }
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("Invalid path {}", path.display()))?;
+    let temp_path = path.with_file_name(format!(".{}.tmp", file_name));
+
+    // A leftover from an earlier attempt may have the wrong permissions
+    let _ = fs::remove_file(&temp_path);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&temp_path)
+        .with_context(|| format!("Creating {}", temp_path.display()))?;
+    file.write_all(content.as_bytes())
+        .with_context(|| format!("Writing {}", temp_path.display()))?;
+    Ok(temp_path)
+}
+
+/// Move a temporary file into place
+fn rename(temp_path: &Path, path: &str) -> anyhow::Result<()> {
+    fs::rename(temp_path, path).with_context(|| {
+        format!("Renaming {} to {}", temp_path.display(), path)
+    })
+}
@@ -0,0 +1,586 @@
+use crate::{
+    config::{DriveMotorLocation, RobotConfig},
+    input::{InputAxis, InputTrigger, TriggerSource},
+    migrate::CONFIG_VERSION,
+};
+use gilrs::{Axis, Button};
+use log::{error, warn};
+use serde::Serialize;
+use std::{collections::HashMap, fmt, net::SocketAddr, path::Path};
+
+/// Valid (non-reserved) 7-bit I2C addresses
+pub const I2C_ADDRESSES: std::ops::RangeInclusive<u8> = 0x03..=0x77;
+
+/// How bad a config problem is
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// The robot can run, but probably not how you want it to
+    Warning,
+    /// The config can't be used
+    Error,
+}
+
+/// A single problem found in a config
+#[derive(Clone, Debug, Serialize)]
+pub struct ConfigProblem {
+    pub severity: Severity,
+    /// Dotted path to the offending field, e.g. `drive.i2c_address`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}: {}", severity, self.path, self.message)
+    }
+}
+
+/// All problems found while validating a config
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(transparent)]
+pub struct ConfigProblems(Vec<ConfigProblem>);
+
+impl ConfigProblems {
+    pub fn error(
+        &mut self,
+        path: impl Into<String>,
+        message: impl Into<String>,
+    ) {
+        self.push(Severity::Error, path.into(), message.into());
+    }
+
+    pub fn warning(
+        &mut self,
+        path: impl Into<String>,
+        message: impl Into<String>,
+    ) {
+        self.push(Severity::Warning, path.into(), message.into());
+    }
+
+    fn push(&mut self, severity: Severity, path: String, message: String) {
+        self.0.push(ConfigProblem {
+            severity,
+            path,
+            message,
+        });
+    }
+
+    /// Are there any problems bad enough that the config can't be used?
+    pub fn has_errors(&self) -> bool {
+        self.0
+            .iter()
+            .any(|problem| problem.severity == Severity::Error)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ConfigProblem> {
+        self.0.iter()
+    }
+
+    /// Log every problem, then return an error if any of them are errors
+    pub fn check(&self) -> anyhow::Result<()> {
+        for problem in self.iter() {
+            match problem.severity {
+                Severity::Warning => warn!("Config {}", problem),
+                Severity::Error => error!("Config {}", problem),
+            }
+        }
+        if self.has_errors() {
+            anyhow::bail!("Config is invalid, see errors above");
+        }
+        Ok(())
+    }
+
+    /// Check that an axis is one we can actually read
+    pub fn check_axis(&mut self, path: &str, axis: Axis) {
+        if axis == Axis::Unknown {
+            self.error(path, "Unknown axis");
+        }
+    }
+
+    /// Check that a button is one we can actually read
+    pub fn check_button(&mut self, path: &str, button: Button) {
+        if button == Button::Unknown {
+            self.error(path, "Unknown button");
+        }
+    }
+
+    /// Check a button that can be left unset
+    pub fn check_optional_button(
+        &mut self,
+        path: &str,
+        button: Option<Button>,
+    ) {
+        if let Some(button) = button {
+            self.check_button(path, button);
+        }
+    }
+
+    pub fn check_input_axis(&mut self, path: &str, axis: InputAxis) {
+        self.check_axis(&format!("{}.axis", path), axis.axis);
+        if let Some(filter) = axis.filter {
+            if filter.time_constant_ms < 0.0 {
+                self.error(
+                    format!("{}.filter.time_constant_ms", path),
+                    "Must not be negative",
+                );
+            }
+            if !(0.0..1.0).contains(&filter.hysteresis) {
+                self.error(
+                    format!("{}.filter.hysteresis", path),
+                    format!("{} is outside [0, 1)", filter.hysteresis),
+                );
+            }
+        }
+    }
+
+    pub fn check_trigger(&mut self, path: &str, trigger: InputTrigger) {
+        match trigger.source {
+            TriggerSource::Axis(axis) => {
+                self.check_axis(&format!("{}.axis", path), axis)
+            }
+            TriggerSource::Button(button) => {
+                self.check_button(&format!("{}.button", path), button)
+            }
+        }
+    }
+
+    /// Check that a value is in the range of [-1, 1]
+    pub fn check_speed(&mut self, path: &str, speed: f32) {
+        if !(-1.0..=1.0).contains(&speed) {
+            self.error(path, format!("{} is outside [-1, 1]", speed));
+        }
+    }
+}
+
+impl RobotConfig {
+    /// Check the config for anything that deserialization doesn't catch, and
+    /// get a list of everything that's wrong with it
+    pub fn validate(&self) -> ConfigProblems {
+        let mut problems = ConfigProblems::default();
+        if self.version != CONFIG_VERSION {
+            problems.error(
+                "version",
+                format!(
+                    "Config is version {}, expected {}",
+                    self.version, CONFIG_VERSION
+                ),
+            );
+        }
+        self.validate_resolved(&mut problems);
+
+        if let Some(name) = &self.profile {
+            if !self.profiles.contains_key(name) {
+                problems
+                    .error("profile", format!("No profile named {:?}", name));
+            }
+        }
+
+        // Check every profile with its overrides applied. Problems that the
+        // base config has too were already reported above.
+        for name in self.profiles.keys() {
+            let path = format!("profiles.{}", name);
+            match self.with_profile(name) {
+                Err(err) => problems.error(path, format!("{:#}", err)),
+                Ok(config) => {
+                    let mut profile_problems = ConfigProblems::default();
+                    config.validate_resolved(&mut profile_problems);
+                    for problem in profile_problems.0 {
+                        if !problems.0.iter().any(|other| {
+                            other.path == problem.path
+                                && other.message == problem.message
+                        }) {
+                            problems.push(
+                                problem.severity,
+                                format!("{}: {}", path, problem.path),
+                                problem.message,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Validate everything except the profiles themselves. This is also used
+    /// to check the config with each profile applied.
+    fn validate_resolved(&self, problems: &mut ConfigProblems) {
+        self.validate_input(problems);
+        self.validate_drive(problems);
+
+        if let Err(err) = parse_host(&self.api.host) {
+            problems.error("api.host", err);
+        }
+        // The backup of the current file always counts, so 0 would delete it
+        // as soon as it's made
+        if self.api.max_backups == 0 {
+            problems.error("api.max_backups", "Must be at least 1");
+        }
+        match &self.api.tokens_path {
+            Some(path) if !Path::new(path).exists() => {
+                problems.error("api.tokens_path", format!("{} not found", path))
+            }
+            Some(_) => {}
+            None if !is_loopback(&self.api.host) => problems.error(
+                "api.tokens_path",
+                "Not set, so anyone on the network could control the robot \
+                through the API. Set it, or make api.host a loopback \
+                address like 127.0.0.1:8000.",
+            ),
+            None => {}
+        }
+        self.validate_tls(problems);
+        if self.general.i2c_device_path.is_empty() && !self.drive.simulate {
+            problems.error("general.i2c_device_path", "Must not be empty");
+        }
+    }
+
+    fn validate_tls(&self, problems: &mut ConfigProblems) {
+        let api = &self.api;
+        let tls = &api.tls;
+        if !tls.enabled {
+            if api.tokens_path.is_some() && !is_loopback(&api.host) {
+                problems.warning(
+                    "api.tls.enabled",
+                    "API tokens will be sent over the network unencrypted",
+                );
+            }
+            return;
+        }
+
+        for (path, value) in [
+            ("api.tls.cert_path", &tls.cert_path),
+            ("api.tls.key_path", &tls.key_path),
+        ] {
+            if value.is_empty() {
+                problems.error(path, "Must not be empty");
+            } else if !tls.generate_self_signed && !Path::new(value).exists() {
+                problems.error(path, format!("{} not found", value));
+            }
+        }
+        if tls.generate_self_signed && tls.self_signed_names.is_empty() {
+            problems.error(
+                "api.tls.self_signed_names",
+                "Must have at least one name to generate a certificate for",
+            );
+        }
+    }
+
+    fn validate_input(&self, problems: &mut ConfigProblems) {
+        let input = &self.input;
+
+        // Drive control
+        if input.control.slots.is_empty() {
+            if !input.gamepads.contains_key(&input.drive_gamepad) {
+                problems.warning(
+                    "input.drive_gamepad",
+                    format!("No gamepad slot named {:?}", input.drive_gamepad),
+                );
+            }
+        } else {
+            for (i, slot) in input.control.slots.iter().enumerate() {
+                if !input.gamepads.contains_key(&slot.slot) {
+                    problems.warning(
+                        format!("input.control.slots[{}].slot", i),
+                        format!("No gamepad slot named {:?}", slot.slot),
+                    );
+                }
+            }
+        }
+
+        let control = &input.control;
+        problems.check_optional_button(
+            "input.control.take_control_button",
+            control.take_control_button,
+        );
+        problems.check_optional_button(
+            "input.control.disable_students_button",
+            control.disable_students_button,
+        );
+        problems.check_optional_button(
+            "input.profile_button",
+            input.profile_button,
+        );
+
+        input.drive.mapping().validate("input.drive", problems);
+
+        for axis in input.keyboard.axes.keys() {
+            problems
+                .check_axis(&format!("input.keyboard.axes.{:?}", axis), *axis);
+        }
+
+        // Speed gears
+        let speed = &input.speed;
+        for (i, gear) in speed.gears.iter().enumerate() {
+            if !(0.0..=1.0).contains(gear) {
+                problems.error(
+                    format!("input.speed.gears[{}]", i),
+                    format!("{} is outside [0, 1]", gear),
+                );
+            }
+        }
+        if speed.initial_gear >= speed.gears.len() && !speed.gears.is_empty() {
+            problems.warning(
+                "input.speed.initial_gear",
+                format!(
+                    "There are only {} gears, the highest will be used",
+                    speed.gears.len()
+                ),
+            );
+        }
+        problems.check_speed("input.speed.turbo_speed", speed.turbo_speed);
+        problems
+            .check_speed("input.speed.precision_speed", speed.precision_speed);
+        for (path, button) in [
+            ("input.speed.gear_up_button", speed.gear_up_button),
+            ("input.speed.gear_down_button", speed.gear_down_button),
+            ("input.speed.turbo_button", speed.turbo_button),
+            ("input.speed.precision_button", speed.precision_button),
+        ] {
+            problems.check_optional_button(path, button);
+        }
+
+        // Macros
+        problems.check_optional_button(
+            "input.macros.stop_button",
+            input.macros.stop_button,
+        );
+        for (name, drive_macro) in &input.macros.sequences {
+            problems.check_optional_button(
+                &format!("input.macros.sequences.{}.button", name),
+                drive_macro.button,
+            );
+            for (i, step) in drive_macro.steps.iter().enumerate() {
+                let path =
+                    format!("input.macros.sequences.{}.steps[{}]", name, i);
+                problems.check_speed(&format!("{}.left", path), step.left);
+                problems.check_speed(&format!("{}.right", path), step.right);
+            }
+        }
+    }
+
+    fn validate_drive(&self, problems: &mut ConfigProblems) {
+        let drive = &self.drive;
+        if !I2C_ADDRESSES.contains(&drive.i2c_address) {
+            problems.error(
+                "drive.i2c_address",
+                format!(
+                    "{:#04x} is not a valid 7-bit I2C address ({:#04x}-{:#04x})",
+                    drive.i2c_address,
+                    I2C_ADDRESSES.start(),
+                    I2C_ADDRESSES.end()
+                ),
+            );
+        }
+
+        // Every motor needs exactly one channel, and no two motors can share
+        // a channel
+        let mut locations_by_channel = HashMap::new();
+        for &location in DriveMotorLocation::ALL {
+            let path = format!("drive.motors.{}", location_name(location));
+            match drive.motors.get(&location) {
+                None => problems.error(path, "No motor channel mapped"),
+                Some(channel) => {
+                    if let Some(other) =
+                        locations_by_channel.insert(channel, location)
+                    {
+                        problems.error(
+                            path,
+                            format!(
+                                "Channel {:?} is also mapped to {}",
+                                channel,
+                                location_name(other)
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Get the name of a motor location as it appears in the config
+pub fn location_name(location: DriveMotorLocation) -> &'static str {
+    match location {
+        DriveMotorLocation::FrontLeft => "front_left",
+        DriveMotorLocation::FrontRight => "front_right",
+        DriveMotorLocation::BackLeft => "back_left",
+        DriveMotorLocation::BackRight => "back_right",
+    }
+}
+
+/// Is an API host only reachable from this machine?
+fn is_loopback(host: &str) -> bool {
+    match host.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().is_loopback(),
+        Err(_) => host.starts_with("localhost:"),
+    }
+}
+
+/// Make sure an API host is something we can bind to, i.e. `<ip>:<port>` or
+/// `<hostname>:<port>`
+fn parse_host(host: &str) -> Result<(), String> {
+    if host.parse::<SocketAddr>().is_ok() {
+        return Ok(());
+    }
+    match host.rsplit_once(':') {
+        Some((hostname, port))
+            if !hostname.is_empty() && port.parse::<u16>().is_ok() =>
+        {
+            Ok(())
+        }
+        _ => Err(format!(
+            "{:?} is not a valid address, expected <host>:<port>",
+            host
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{motors::MotorChannel, profiles::Profile};
+    use serde_json::json;
+
+    /// Get the paths of every error in a config
+    fn errors(config: &RobotConfig) -> Vec<String> {
+        config
+            .validate()
+            .iter()
+            .filter(|problem| problem.severity == Severity::Error)
+            .map(|problem| problem.path.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_default_valid() {
+        let problems = RobotConfig::test_default().validate();
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn test_version() {
+        let mut config = RobotConfig::test_default();
+        config.version = CONFIG_VERSION - 1;
+        assert_eq!(errors(&config), ["version"]);
+    }
+
+    #[test]
+    fn test_drive() {
+        let mut config = RobotConfig::test_default();
+        config.drive.i2c_address = 0x78;
+        config
+            .drive
+            .motors
+            .insert(DriveMotorLocation::BackRight, MotorChannel::Motor1);
+        config.drive.motors.remove(&DriveMotorLocation::BackLeft);
+        let mut errors = errors(&config);
+        errors.sort();
+        assert_eq!(
+            errors,
+            [
+                "drive.i2c_address",
+                "drive.motors.back_left",
+                "drive.motors.back_right"
+            ]
+        );
+    }
+
+    /// Every button in the input config is checked, not just triggers
+    #[test]
+    fn test_unknown_buttons() {
+        let mut config = RobotConfig::test_default();
+        config.input.profile_button = Some(Button::Unknown);
+        config.input.speed.turbo_button = Some(Button::Unknown);
+        config.input.macros.stop_button = Some(Button::Unknown);
+        config.input.control.take_control_button = Some(Button::Unknown);
+        let mut errors = errors(&config);
+        errors.sort();
+        assert_eq!(
+            errors,
+            [
+                "input.control.take_control_button",
+                "input.macros.stop_button",
+                "input.profile_button",
+                "input.speed.turbo_button",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_api() {
+        let mut config = RobotConfig::test_default();
+        config.api.max_backups = 0;
+        assert_eq!(errors(&config), ["api.max_backups"]);
+
+        // Anyone on the network could use an API without tokens
+        let mut config = RobotConfig::test_default();
+        config.api.host = "0.0.0.0:8000".into();
+        assert_eq!(errors(&config), ["api.tokens_path"]);
+        config.api.host = "localhost:8000".into();
+        assert!(errors(&config).is_empty());
+    }
+
+    /// Profiles are checked with their overrides applied, and only report
+    /// problems that the base config doesn't already have
+    #[test]
+    fn test_profiles() {
+        let mut config = RobotConfig::test_default();
+        config.drive.i2c_address = 0x78;
+        config.profiles.insert(
+            "fast".into(),
+            Profile {
+                input: Some(json!({"speed": {"turbo_speed": 2.0}})),
+                ..Default::default()
+            },
+        );
+        config.profiles.insert(
+            "broken".into(),
+            Profile {
+                drive: Some(json!({"i2c_address": "nope"})),
+                ..Default::default()
+            },
+        );
+        config.profile = Some("missing".into());
+        let mut errors = errors(&config);
+        errors.sort();
+        assert_eq!(
+            errors,
+            [
+                "drive.i2c_address",
+                "profile",
+                "profiles.broken",
+                "profiles.fast: input.speed.turbo_speed"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_host() {
+        assert!(parse_host("127.0.0.1:8000").is_ok());
+        assert!(parse_host("[::1]:8000").is_ok());
+        assert!(parse_host("robot.local:8000").is_ok());
+        assert!(parse_host("robot.local").is_err());
+        assert!(parse_host(":8000").is_err());
+        assert!(parse_host("robot.local:99999").is_err());
+    }
+
+    #[test]
+    fn test_is_loopback() {
+        assert!(is_loopback("127.0.0.1:8000"));
+        assert!(is_loopback("[::1]:8000"));
+        assert!(is_loopback("localhost:8000"));
+        assert!(!is_loopback("0.0.0.0:8000"));
+        assert!(!is_loopback("robot.local:8000"));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{
+    config::{ConfigSources, RobotConfig},
+    history::ConfigHistory,
+};
+use anyhow::Context;
+use async_std::{sync::RwLock, task};
+use log::{debug, error, info};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// Wait this long after a file changes before reloading, so a deploy that
+/// writes several files only triggers one reload
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(500);
+
+/// Start watching all config files for changes, on a background thread. When
+/// any of them change, the config is reloaded from all sources and
+/// validated. If it's valid, it replaces the shared config and is recorded in
+/// the history. Otherwise, the errors are logged and the current config is
+/// kept.
+///
+/// This watches the directories containing the config files rather than the
+/// files themselves, so files that are replaced (e.g. by rsync) or created
+/// later are picked up too.
+pub fn watch_config(
+    sources: ConfigSources,
+    config: Arc<RwLock<RobotConfig>>,
+    history: Arc<Mutex<ConfigHistory>>,
+) -> anyhow::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, DEBOUNCE_DELAY)?;
+
+    let mut paths = HashSet::new();
+    let mut directories = HashSet::new();
+    for path in sources.paths() {
+        let (directory, path) = absolute_path(Path::new(path))?;
+        paths.insert(path);
+        directories.insert(directory);
+    }
+    for directory in &directories {
+        info!("Watching {} for config changes", directory.display());
+        watcher
+            .watch(directory, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Watching {}", directory.display()))?;
+    }
+
+    thread::Builder::new()
+        .name("config-watcher".into())
+        .spawn(move || {
+            // Keep the watcher alive for as long as we're receiving from it
+            let _watcher = watcher;
+            for event in rx {
+                let changed_path = match event {
+                    DebouncedEvent::Create(path)
+                    | DebouncedEvent::Write(path)
+                    | DebouncedEvent::Rename(_, path) => path,
+                    DebouncedEvent::Error(err, _) => {
+                        error!("Error watching config files: {}", err);
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if paths.contains(&changed_path) {
+                    info!(
+                        "{} changed, reloading config",
+                        changed_path.display()
+                    );
+                    reload_config(&sources, &config, &history);
+                } else {
+                    debug!("Ignoring change to {}", changed_path.display());
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Reload the config from all sources, and replace the shared config if the
+/// new one is valid
+fn reload_config(
+    sources: &ConfigSources,
+    config: &RwLock<RobotConfig>,
+    history: &Mutex<ConfigHistory>,
+) {
+    let new_config = match RobotConfig::load(sources) {
+        Ok(new_config) => new_config,
+        Err(err) => {
+            error!("Error reloading config, keeping the old one: {:?}", err);
+            return;
+        }
+    };
+    if let Err(err) = new_config.validate().check() {
+        error!("Not applying new config: {}", err);
+        return;
+    }
+
+    // The main loop holds a read lock for each whole iteration, so this swaps
+    // the config in between iterations
+    let mut config = task::block_on(config.write());
+    // Writes that already applied the config, e.g. persisted API changes,
+    // trigger a reload too. Don't record those twice.
+    if serde_json::to_value(&*config).ok()
+        == serde_json::to_value(&new_config).ok()
+    {
+        info!("Config is unchanged, nothing to apply");
+        return;
+    }
+    history
+        .lock()
+        .unwrap()
+        .record(&new_config, None, "Reloaded config files");
+    *config = new_config;
+    info!("Applied new config");
+}
+
+/// Get the absolute path of a config file, and the directory it's in. The file
+/// doesn't need to exist, but its directory does.
+fn absolute_path(path: &Path) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let directory = fs::canonicalize(directory)
+        .with_context(|| format!("Resolving {}", directory.display()))?;
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{} is not a file", path.display()))?;
+    let path = directory.join(file_name);
+    Ok((directory, path))
+}